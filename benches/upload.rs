@@ -0,0 +1,104 @@
+//! Measures [`EguiWgpu::prepare`] for UIs with thousands of meshes, with every
+//! [`BufferUpload`] method. Run with `cargo bench --bench upload`.
+
+use std::time::{Duration, Instant};
+
+use egui_wgpu::{BufferUpload, EguiWgpu, PaintStats, TargetFormat};
+
+const WIDTH: u32 = 1920;
+const HEIGHT: u32 = 1080;
+const FRAMES: u32 = 200;
+
+/// Paints `count` small rects, each with its own clip rect so that egui
+/// tessellates every one of them into a separate mesh.
+fn many_meshes(ctx: &egui::Context, count: usize) {
+    egui::CentralPanel::default().show(ctx, |ui| {
+        let columns = 64;
+        for i in 0..count {
+            let min = egui::pos2((i % columns) as f32 * 12.0, (i / columns) as f32 * 12.0);
+            let rect = egui::Rect::from_min_size(min, egui::vec2(10.0, 10.0));
+            ui.painter()
+                .sub_region(rect)
+                .rect_filled(rect, 2.0, egui::Color32::from_gray((i % 256) as u8));
+        }
+    });
+}
+
+/// Average time of [`EguiWgpu::prepare`], including tessellation, and the
+/// stats of the last frame.
+fn bench(
+    device: &wgpu::Device,
+    queue: &wgpu::Queue,
+    upload: BufferUpload,
+    meshes: usize,
+) -> (Duration, PaintStats) {
+    let max_texture_side = device.limits().max_texture_dimension_2d as usize;
+    let mut egui_wgpu =
+        EguiWgpu::new_headless(device, max_texture_side, 1.0).with_buffer_upload(upload);
+    let target_format = TargetFormat::new(wgpu::TextureFormat::Rgba8UnormSrgb);
+    let mut total = Duration::ZERO;
+    let mut stats = PaintStats::default();
+    for frame in 0..FRAMES + 1 {
+        let raw_input = egui::RawInput {
+            screen_rect: Some(egui::Rect::from_min_size(
+                egui::Pos2::ZERO,
+                egui::vec2(WIDTH as f32, HEIGHT as f32),
+            )),
+            ..Default::default()
+        };
+        egui_wgpu.run_with_input(raw_input, |ctx| many_meshes(ctx, meshes));
+        let start = Instant::now();
+        stats = egui_wgpu
+            .prepare(device, queue, &target_format, WIDTH, HEIGHT)
+            .expect("failed to prepare");
+        // The first frame uploads the font atlas and allocates buffers.
+        if frame > 0 {
+            total += start.elapsed();
+        }
+        device.poll(wgpu::Maintain::Poll);
+    }
+    (total / FRAMES, stats)
+}
+
+fn main() {
+    let instance = wgpu::Instance::new(wgpu::Backends::all());
+    let adapter = [false, true].iter().find_map(|&force_fallback_adapter| {
+        pollster::block_on(instance.request_adapter(&wgpu::RequestAdapterOptions {
+            power_preference: wgpu::PowerPreference::HighPerformance,
+            compatible_surface: None,
+            force_fallback_adapter,
+        }))
+    });
+    let adapter = match adapter {
+        Some(adapter) => adapter,
+        None => {
+            eprintln!("no wgpu adapter, skipping upload benchmarks");
+            return;
+        }
+    };
+    let (device, queue) = pollster::block_on(adapter.request_device(
+        &wgpu::DeviceDescriptor {
+            features: wgpu::Features::empty(),
+            limits: wgpu::Limits::default(),
+            label: None,
+        },
+        None,
+    ))
+    .expect("failed to create device");
+    println!("{:?}", adapter.get_info());
+
+    for meshes in [1_000, 5_000] {
+        for upload in [BufferUpload::WriteBuffer, BufferUpload::StagingBelt] {
+            let (time, stats) = bench(&device, &queue, upload, meshes);
+            println!(
+                "{:>5} meshes, {:?}: {:?} per frame, {} bytes written, {} draw calls ({} unbatched)",
+                meshes,
+                upload,
+                time,
+                stats.buffer_bytes_written,
+                stats.draw_calls,
+                stats.unbatched_draw_calls,
+            );
+        }
+    }
+}
@@ -3,6 +3,8 @@ use egui_wgpu::EguiWgpu;
 use egui_wgpu::RenderTarget;
 use egui_winit::winit;
 
+const MSAA_SAMPLES: u32 = 4;
+
 fn main() {
     let event_loop = winit::event_loop::EventLoop::new();
     let window = winit::window::WindowBuilder::new()
@@ -30,7 +32,8 @@ fn main() {
 
     let (device, queue) = pollster::block_on(adapter.request_device(
         &wgpu::DeviceDescriptor {
-            features: wgpu::Features::default(),
+            // Optional, lets `EguiWgpu::gpu_time_ms` measure the egui render pass.
+            features: adapter.features() & wgpu::Features::TIMESTAMP_QUERY,
             limits: wgpu::Limits::default(),
             label: None,
         },
@@ -43,12 +46,17 @@ fn main() {
     let mut surface_config = wgpu::SurfaceConfiguration {
         usage: wgpu::TextureUsages::RENDER_ATTACHMENT,
         format: surface_format,
-        width: size.width as u32,
-        height: size.height as u32,
+        width: size.width,
+        height: size.height,
         present_mode: wgpu::PresentMode::Fifo,
     };
     surface.configure(&device, &surface_config);
-    let pipeline = egui_wgpu::Pipeline::new(&device, surface_format, 1);
+    let target_format = egui_wgpu::TargetFormat {
+        sample_count: MSAA_SAMPLES,
+        ..egui_wgpu::TargetFormat::new(surface_format)
+    };
+    let mut msaa_texture =
+        target_format.create_msaa_texture(&device, surface_config.width, surface_config.height);
 
     let mut name = String::new();
     let mut age = 0;
@@ -56,12 +64,17 @@ fn main() {
     event_loop.run(move |event, _target, cf| {
         //
         match event {
-            winit::event::Event::WindowEvent { window_id, event } => {
+            winit::event::Event::WindowEvent { event, .. } => {
                 match &event {
                     winit::event::WindowEvent::Resized(size) => {
                         surface_config.width = size.width;
                         surface_config.height = size.height;
                         surface.configure(&device, &surface_config);
+                        msaa_texture = target_format.create_msaa_texture(
+                            &device,
+                            surface_config.width,
+                            surface_config.height,
+                        );
                     }
                     winit::event::WindowEvent::CloseRequested => {
                         *cf = winit::event_loop::ControlFlow::Exit;
@@ -74,6 +87,7 @@ fn main() {
             }
             winit::event::Event::RedrawRequested(_) => {
                 let mut quit = false;
+                let gpu_time_ms = egui_wgpu.gpu_time_ms();
                 let needs_repaint = egui_wgpu.run(&window, |egui_ctx| {
                     egui::SidePanel::left("my_side_panel").show(egui_ctx, |ui| {
                         ui.label(RichText::new("Hello World! 129034").size(18.0));
@@ -89,6 +103,9 @@ fn main() {
                         ui.label(
                             RichText::new(format!("Hello '{}', age {}", name, age)).size(36.0),
                         );
+                        if let Some(ms) = gpu_time_ms {
+                            ui.label(format!("egui GPU time: {:.3} ms", ms));
+                        }
                         if ui.button("Quit").clicked() {
                             quit = true;
                             println!("Quit Click");
@@ -109,14 +126,20 @@ fn main() {
                     let view = t.texture.create_view(&wgpu::TextureViewDescriptor {
                         ..Default::default()
                     });
+                    let msaa_view = msaa_texture.create_view(&Default::default());
                     let target = RenderTarget {
-                        view: &view,
+                        view: &msaa_view,
+                        resolve_target: Some(&view),
+                        depth_view: None,
+                        format: target_format,
                         clear_color: Some(wgpu::Color::TRANSPARENT),
                         width: surface_config.width,
                         height: surface_config.height,
                     };
 
-                    egui_wgpu.paint(&device, &queue, &pipeline, target);
+                    if let Err(err) = egui_wgpu.paint(&device, &queue, target) {
+                        eprintln!("failed to paint egui: {}", err);
+                    }
                     t.present();
                 }
             }
@@ -0,0 +1,150 @@
+use egui_wgpu::EguiWgpu;
+use egui_wgpu::RenderTarget;
+use egui_winit::winit;
+
+/// Everything that belongs to one window. The device, queue and the egui
+/// [`egui_wgpu::Renderer`] are shared by all of them.
+struct Window {
+    window: winit::window::Window,
+    surface: wgpu::Surface,
+    surface_config: wgpu::SurfaceConfiguration,
+    egui_wgpu: EguiWgpu,
+}
+
+fn build_window(
+    event_loop: &winit::event_loop::EventLoop<()>,
+    title: &str,
+) -> winit::window::Window {
+    winit::window::WindowBuilder::new()
+        .with_title(title)
+        .with_inner_size(winit::dpi::PhysicalSize {
+            width: 400,
+            height: 300,
+        })
+        .build(event_loop)
+        .unwrap()
+}
+
+fn surface_config(
+    format: wgpu::TextureFormat,
+    size: winit::dpi::PhysicalSize<u32>,
+) -> wgpu::SurfaceConfiguration {
+    wgpu::SurfaceConfiguration {
+        usage: wgpu::TextureUsages::RENDER_ATTACHMENT,
+        format,
+        width: size.width,
+        height: size.height,
+        present_mode: wgpu::PresentMode::Fifo,
+    }
+}
+
+fn main() {
+    let event_loop = winit::event_loop::EventLoop::new();
+    let main_window = build_window(&event_loop, "egui-wgpu main window");
+    let tool_window = build_window(&event_loop, "egui-wgpu tool window");
+
+    let instance = wgpu::Instance::new(wgpu::Backends::PRIMARY);
+    let main_surface = unsafe { instance.create_surface(&main_window) };
+    let tool_surface = unsafe { instance.create_surface(&tool_window) };
+    let adapter = pollster::block_on(instance.request_adapter(&wgpu::RequestAdapterOptions {
+        power_preference: wgpu::PowerPreference::HighPerformance,
+        compatible_surface: Some(&main_surface),
+        force_fallback_adapter: false,
+    }))
+    .unwrap();
+    let (device, queue) = pollster::block_on(adapter.request_device(
+        &wgpu::DeviceDescriptor {
+            features: wgpu::Features::default(),
+            limits: wgpu::Limits::default(),
+            label: None,
+        },
+        None,
+    ))
+    .unwrap();
+
+    let surface_format = main_surface.get_preferred_format(&adapter).unwrap();
+
+    let main_egui = EguiWgpu::new(&adapter, &device, &main_window);
+    let tool_egui = EguiWgpu::with_renderer(main_egui.renderer().clone(), &adapter, &tool_window);
+    let mut windows: Vec<Window> = [
+        (main_window, main_surface, main_egui),
+        (tool_window, tool_surface, tool_egui),
+    ]
+    .into_iter()
+    .map(|(window, surface, egui_wgpu)| {
+        let surface_config = surface_config(surface_format, window.inner_size());
+        surface.configure(&device, &surface_config);
+        Window {
+            window,
+            surface,
+            surface_config,
+            egui_wgpu,
+        }
+    })
+    .collect();
+
+    let mut counter = 0;
+    event_loop.run(move |event, _target, cf| match event {
+        winit::event::Event::WindowEvent { window_id, event } => {
+            let window = match windows.iter_mut().find(|w| w.window.id() == window_id) {
+                Some(window) => window,
+                None => return,
+            };
+            match &event {
+                winit::event::WindowEvent::Resized(size) => {
+                    window.surface_config.width = size.width;
+                    window.surface_config.height = size.height;
+                    window.surface.configure(&device, &window.surface_config);
+                }
+                winit::event::WindowEvent::CloseRequested => {
+                    *cf = winit::event_loop::ControlFlow::Exit;
+                }
+                _ => {}
+            }
+            window.egui_wgpu.on_event(&event);
+            window.window.request_redraw();
+        }
+        winit::event::Event::RedrawRequested(window_id) => {
+            let index = match windows.iter().position(|w| w.window.id() == window_id) {
+                Some(index) => index,
+                None => return,
+            };
+            let old_counter = counter;
+            let window = &mut windows[index];
+            window.egui_wgpu.run(&window.window, |egui_ctx| {
+                egui::CentralPanel::default().show(egui_ctx, |ui| {
+                    ui.label(if index == 0 { "Main window" } else { "Tool window" });
+                    if ui.button(format!("Clicked {} times", counter)).clicked() {
+                        counter += 1;
+                    }
+                });
+            });
+            // The counter is shown in every window.
+            if counter != old_counter {
+                for window in &windows {
+                    window.window.request_redraw();
+                }
+            }
+            *cf = winit::event_loop::ControlFlow::Wait;
+
+            let window = &mut windows[index];
+            if let Ok(t) = window.surface.get_current_texture() {
+                let view = t.texture.create_view(&Default::default());
+                let target = RenderTarget {
+                    view: &view,
+                    resolve_target: None,
+                    depth_view: None,
+                    format: egui_wgpu::TargetFormat::new(surface_format),
+                    clear_color: Some(wgpu::Color::TRANSPARENT),
+                    width: window.surface_config.width,
+                    height: window.surface_config.height,
+                };
+                if let Err(err) = window.egui_wgpu.paint(&device, &queue, target) {
+                    eprintln!("failed to paint egui: {}", err);
+                }
+                t.present();
+            }
+        }
+        _ => {}
+    });
+}
@@ -0,0 +1,129 @@
+use wgpu::{BufferUsages, Device};
+
+use crate::{painter::PaintStats, pipeline::SizedBuffer, upload::Uploader};
+
+/// Smallest buffer allocated, in bytes.
+const MIN_CAPACITY: usize = 4096;
+
+/// Consecutive frames using less than a quarter of the buffer after which it
+/// is shrunk.
+const SHRINK_AFTER_FRAMES: u32 = 120;
+
+/// A vertex or index buffer holding the data of all meshes of a frame.
+///
+/// Grows to the next power of two when a frame doesn't fit and shrinks once
+/// frames have used less than a quarter of it for a while, so its size follows
+/// the UI without reallocating every frame.
+pub struct BufferArena {
+    label: &'static str,
+    usage: BufferUsages,
+    buffer: Option<SizedBuffer>,
+    /// Consecutive frames that used less than a quarter of `buffer`.
+    low_use_frames: u32,
+}
+
+impl BufferArena {
+    pub fn new(label: &'static str, usage: BufferUsages) -> Self {
+        Self {
+            label,
+            usage,
+            buffer: None,
+            low_use_frames: 0,
+        }
+    }
+
+    /// Replaces the contents of the buffer with `parts` back to back,
+    /// reallocating it first if its capacity changes. The length of every part
+    /// must be a multiple of [`wgpu::COPY_BUFFER_ALIGNMENT`].
+    pub fn upload(
+        &mut self,
+        device: &Device,
+        queue: &wgpu::Queue,
+        uploader: &mut Uploader,
+        stats: &mut PaintStats,
+        parts: &[&[u8]],
+    ) {
+        let len = parts.iter().map(|part| part.len()).sum();
+        let current = self.buffer.as_ref().map_or(0, |b| b.size);
+        let capacity = next_capacity(current, len, &mut self.low_use_frames);
+        if capacity != current {
+            stats.buffers_allocated += 1;
+            self.buffer = Some(SizedBuffer {
+                buffer: device.create_buffer(&wgpu::BufferDescriptor {
+                    label: Some(self.label),
+                    size: capacity as u64,
+                    usage: self.usage | BufferUsages::COPY_DST,
+                    mapped_at_creation: false,
+                }),
+                size: capacity,
+            });
+        }
+        if let Some(buffer) = &self.buffer {
+            let mut offset = 0;
+            for part in parts {
+                uploader.write(device, queue, &buffer.buffer, offset, part);
+                offset += part.len() as u64;
+            }
+            stats.buffer_bytes_written += len as u64;
+        }
+    }
+
+    /// `None` until a frame uploaded some data.
+    pub fn buffer(&self) -> Option<&wgpu::Buffer> {
+        self.buffer.as_ref().map(|b| &b.buffer)
+    }
+}
+
+/// Capacity for `needed` bytes of a buffer of `current` bytes, `0` if there is
+/// none yet.
+fn next_capacity(current: usize, needed: usize, low_use_frames: &mut u32) -> usize {
+    let fit = |bytes: usize| bytes.next_power_of_two().max(MIN_CAPACITY);
+    if needed > current {
+        *low_use_frames = 0;
+        return fit(needed);
+    }
+    if needed * 4 >= current || current == MIN_CAPACITY {
+        *low_use_frames = 0;
+        return current;
+    }
+    *low_use_frames += 1;
+    if *low_use_frames < SHRINK_AFTER_FRAMES {
+        return current;
+    }
+    *low_use_frames = 0;
+    // Leave room to grow again without reallocating.
+    fit(needed * 2)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn grows_by_powers_of_two() {
+        let mut low_use_frames = 0;
+        assert_eq!(next_capacity(0, 0, &mut low_use_frames), 0);
+        assert_eq!(next_capacity(0, 10, &mut low_use_frames), MIN_CAPACITY);
+        assert_eq!(next_capacity(4096, 4097, &mut low_use_frames), 8192);
+        assert_eq!(next_capacity(8192, 5000, &mut low_use_frames), 8192);
+        assert_eq!(next_capacity(8192, 100_000, &mut low_use_frames), 131_072);
+    }
+
+    #[test]
+    fn shrinks_after_sustained_low_use() {
+        let mut low_use_frames = 0;
+        let capacity = 1 << 20;
+        for _ in 1..SHRINK_AFTER_FRAMES {
+            assert_eq!(next_capacity(capacity, 10_000, &mut low_use_frames), capacity);
+        }
+        assert_eq!(next_capacity(capacity, 10_000, &mut low_use_frames), 32_768);
+        assert_eq!(low_use_frames, 0);
+
+        // A busy frame resets the count.
+        for _ in 1..SHRINK_AFTER_FRAMES {
+            next_capacity(capacity, 10_000, &mut low_use_frames);
+        }
+        assert_eq!(next_capacity(capacity, capacity / 2, &mut low_use_frames), capacity);
+        assert_eq!(next_capacity(capacity, 10_000, &mut low_use_frames), capacity);
+    }
+}
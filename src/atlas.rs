@@ -0,0 +1,661 @@
+use std::sync::Arc;
+
+use ahash::AHashMap;
+use wgpu::{BindGroup, Device, Texture};
+
+use crate::painter::{write_texture, Renderer, SamplerOptions, TextureOptions};
+
+/// Texels around every image in the atlas, copies of its edge, so that linear
+/// filtering near the edge doesn't pick up the neighboring images.
+const PADDING: u32 = 1;
+
+pub(crate) const FORMAT: wgpu::TextureFormat = wgpu::TextureFormat::Rgba8UnormSrgb;
+
+/// Packing of small egui managed color images into shared textures, see
+/// [`crate::EguiWgpu::with_texture_atlas`].
+///
+/// Meshes of images on the same atlas page share a bind group, so they can be
+/// batched into one draw call. Their texture coordinates are remapped to the
+/// page when the meshes are uploaded.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct AtlasOptions {
+    /// Images up to this many texels wide and high are packed, larger ones get
+    /// their own texture.
+    pub max_image_side: u32,
+    /// Width and height of the atlas pages, limited by the device and large
+    /// enough for a padded 1x1 image.
+    pub page_size: u32,
+}
+
+impl Default for AtlasOptions {
+    fn default() -> Self {
+        Self {
+            max_image_side: 128,
+            page_size: 1024,
+        }
+    }
+}
+
+/// `options` limited to pages the device supports and images that fit into
+/// them with their padding.
+fn clamp_options(options: AtlasOptions, max_texture_side: u32) -> AtlasOptions {
+    let page_size = options
+        .page_size
+        .clamp(1 + 2 * PADDING, max_texture_side.max(1 + 2 * PADDING));
+    AtlasOptions {
+        max_image_side: options.max_image_side.min(page_size - 2 * PADDING),
+        page_size,
+    }
+}
+
+/// One texture of the atlas, holding images sampled with `sampler`.
+struct Page {
+    sampler: SamplerOptions,
+    texture: Texture,
+    bind: Arc<BindGroup>,
+    allocator: ShelfAllocator,
+}
+
+/// The atlas of one [`crate::EguiWgpu`], keyed by [`egui::TextureId::Managed`].
+pub struct Atlas {
+    options: AtlasOptions,
+    pages: Vec<Page>,
+    /// Page of every packed image.
+    entries: AHashMap<egui::TextureId, usize>,
+}
+
+impl Atlas {
+    pub fn new(device: &Device, options: AtlasOptions) -> Self {
+        Self {
+            options: clamp_options(options, device.limits().max_texture_dimension_2d),
+            pages: Vec::new(),
+            entries: Default::default(),
+        }
+    }
+
+    /// Whether a color image of `size` with `options` can be packed.
+    /// Mipmaps and wrapping other than clamping need a texture of their own.
+    pub fn accepts(&self, size: [u32; 2], options: &TextureOptions) -> bool {
+        size[0].max(size[1]) <= self.options.max_image_side
+            && !options.mipmaps
+            && options.sampler.wrap_mode == wgpu::AddressMode::ClampToEdge
+    }
+
+    /// Size of the packed image `id`, `None` if it isn't in the atlas.
+    pub fn image_size(&self, id: egui::TextureId) -> Option<[u32; 2]> {
+        let page = self.entries.get(&id)?;
+        let [_, _, width, height] = self.pages[*page].allocator.region(id)?;
+        Some([width - 2 * PADDING, height - 2 * PADDING])
+    }
+
+    /// The bind group of the page holding `id` and the rectangle of the image
+    /// in the page's texture coordinates.
+    pub fn lookup(&self, id: egui::TextureId) -> Option<(&Arc<BindGroup>, egui::Rect)> {
+        let page = &self.pages[*self.entries.get(&id)?];
+        let [x, y, width, height] = page.allocator.region(id)?;
+        let page_size = self.options.page_size as f32;
+        let uv_rect = egui::Rect::from_min_size(
+            (egui::vec2((x + PADDING) as f32, (y + PADDING) as f32) / page_size).to_pos2(),
+            egui::vec2((width - 2 * PADDING) as f32, (height - 2 * PADDING) as f32) / page_size,
+        );
+        Some((&page.bind, uv_rect))
+    }
+
+    /// Packs the sRGBA image `data` of `size` as `id`, replacing the previous
+    /// image of that id.
+    #[allow(clippy::too_many_arguments)]
+    pub fn upload(
+        &mut self,
+        renderer: &mut Renderer,
+        device: &Device,
+        queue: &wgpu::Queue,
+        id: egui::TextureId,
+        size: [u32; 2],
+        data: &[u8],
+        sampler: SamplerOptions,
+    ) {
+        // Keep the old page until the new region is allocated, so replacing
+        // the only image on a page reuses it instead of adding a new one.
+        if let Some(page) = self.entries.remove(&id) {
+            self.pages[page].allocator.free(id);
+        }
+        let page = self.allocate(renderer, device, queue, id, size, sampler);
+        let [x, y, width, height] = self.pages[page].allocator.region(id).unwrap();
+        let padded = pad_image(data, size, [PADDING; 4]);
+        write_texture(queue, &self.pages[page].texture, [x, y], [width, height], &padded, 4);
+        self.entries.insert(id, page);
+        self.release_empty_pages();
+    }
+
+    /// Writes the sRGBA image `data` of `size` into the packed image `id` at
+    /// `origin`, which the caller has checked to fit.
+    pub fn update(
+        &self,
+        queue: &wgpu::Queue,
+        id: egui::TextureId,
+        origin: [u32; 2],
+        size: [u32; 2],
+        data: &[u8],
+    ) {
+        let (page, image_size) = match (self.entries.get(&id), self.image_size(id)) {
+            (Some(page), Some(image_size)) => (&self.pages[*page], image_size),
+            _ => return,
+        };
+        let [x, y, _, _] = page.allocator.region(id).unwrap();
+        // Renew the copies of the image's edge the update touches.
+        let pad = |touches_edge: bool| if touches_edge { PADDING } else { 0 };
+        let padding = [
+            pad(origin[0] == 0),
+            pad(origin[1] == 0),
+            pad(origin[0] + size[0] == image_size[0]),
+            pad(origin[1] + size[1] == image_size[1]),
+        ];
+        let padded = pad_image(data, size, padding);
+        write_texture(
+            queue,
+            &page.texture,
+            [
+                x + PADDING + origin[0] - padding[0],
+                y + PADDING + origin[1] - padding[1],
+            ],
+            [
+                size[0] + padding[0] + padding[2],
+                size[1] + padding[1] + padding[3],
+            ],
+            &padded,
+            4,
+        );
+    }
+
+    /// Moves the packed image `id` to a page sampled with `sampler`.
+    pub fn set_sampler(
+        &mut self,
+        renderer: &mut Renderer,
+        device: &Device,
+        queue: &wgpu::Queue,
+        id: egui::TextureId,
+        sampler: SamplerOptions,
+    ) {
+        let old_page = match self.entries.get(&id) {
+            Some(&page) if self.pages[page].sampler != sampler => page,
+            _ => return,
+        };
+        let [x, y, width, height] = self.pages[old_page].allocator.region(id).unwrap();
+        let image_size = [width - 2 * PADDING, height - 2 * PADDING];
+        // Pages with other samplers aren't considered, so this leaves the old
+        // region alone.
+        let new_page = self.allocate(renderer, device, queue, id, image_size, sampler);
+        let region = self.pages[new_page].allocator.region(id).unwrap();
+        self.pages[old_page].allocator.free(id);
+        copy_region(
+            device,
+            queue,
+            &self.pages[old_page].texture,
+            [x, y],
+            &self.pages[new_page].texture,
+            [region[0], region[1]],
+            [width, height],
+        );
+        self.entries.insert(id, new_page);
+        self.release_empty_pages();
+    }
+
+    /// Removes the packed image `id` from the atlas and returns a copy of it in
+    /// a texture of its own.
+    pub fn take(
+        &mut self,
+        device: &Device,
+        queue: &wgpu::Queue,
+        id: egui::TextureId,
+    ) -> Option<(Texture, [u32; 2])> {
+        let page = self.entries.remove(&id)?;
+        let [x, y, width, height] = self.pages[page].allocator.free(id)?;
+        let size = [width - 2 * PADDING, height - 2 * PADDING];
+        let texture = device.create_texture(&wgpu::TextureDescriptor {
+            label: None,
+            size: wgpu::Extent3d {
+                width: size[0],
+                height: size[1],
+                depth_or_array_layers: 1,
+            },
+            mip_level_count: 1,
+            sample_count: 1,
+            dimension: wgpu::TextureDimension::D2,
+            format: FORMAT,
+            usage: wgpu::TextureUsages::COPY_DST | wgpu::TextureUsages::TEXTURE_BINDING,
+        });
+        copy_region(
+            device,
+            queue,
+            &self.pages[page].texture,
+            [x + PADDING, y + PADDING],
+            &texture,
+            [0, 0],
+            size,
+        );
+        self.release_empty_pages();
+        Some((texture, size))
+    }
+
+    /// Frees the region of `id`. Returns `false` if it isn't in the atlas.
+    pub fn free(&mut self, id: egui::TextureId) -> bool {
+        match self.entries.remove(&id) {
+            Some(page) => {
+                self.pages[page].allocator.free(id);
+                self.release_empty_pages();
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Memory used by all pages.
+    pub fn resident_bytes(&self) -> u64 {
+        let page_size = self.options.page_size as u64;
+        self.pages.len() as u64 * page_size * page_size * 4
+    }
+
+    /// Drops the pages without images, so that [`Self::resident_bytes`] shrinks
+    /// again once images are freed.
+    fn release_empty_pages(&mut self) {
+        let released = retain_pages(&mut self.pages, &mut self.entries, |page| {
+            !page.allocator.is_empty()
+        });
+        if released > 0 {
+            log::debug!("released {} empty atlas pages", released);
+        }
+    }
+
+    /// Allocates the region of an image of `size` on a page sampled with
+    /// `sampler`, compacting fragmented pages or adding one if none has room.
+    fn allocate(
+        &mut self,
+        renderer: &mut Renderer,
+        device: &Device,
+        queue: &wgpu::Queue,
+        id: egui::TextureId,
+        size: [u32; 2],
+        sampler: SamplerOptions,
+    ) -> usize {
+        let padded = [size[0] + 2 * PADDING, size[1] + 2 * PADDING];
+        let candidates: Vec<usize> = (0..self.pages.len())
+            .filter(|&page| self.pages[page].sampler == sampler)
+            .collect();
+        for &page in &candidates {
+            if self.pages[page].allocator.allocate(id, padded).is_some() {
+                return page;
+            }
+        }
+        let area = padded[0] as u64 * padded[1] as u64;
+        for &page in &candidates {
+            if self.pages[page].allocator.free_area() >= area
+                && self.compact(renderer, device, queue, page)
+                && self.pages[page].allocator.allocate(id, padded).is_some()
+            {
+                return page;
+            }
+        }
+
+        log::debug!("adding atlas page {} for {:?}", self.pages.len(), sampler);
+        let allocator = ShelfAllocator::new(self.options.page_size);
+        let mut page = self.new_page(renderer, device, sampler, allocator);
+        page.allocator
+            .allocate(id, padded)
+            .expect("image larger than an atlas page");
+        self.pages.push(page);
+        self.pages.len() - 1
+    }
+
+    /// Repacks the images of `page` into a new texture. Returns `false` if
+    /// they don't fit any better.
+    fn compact(
+        &mut self,
+        renderer: &mut Renderer,
+        device: &Device,
+        queue: &wgpu::Queue,
+        page: usize,
+    ) -> bool {
+        let (allocator, moves) = match self.pages[page].allocator.compacted() {
+            Some(compacted) => compacted,
+            None => return false,
+        };
+        log::debug!("compacting atlas page {}", page);
+        let sampler = self.pages[page].sampler;
+        let new_page = self.new_page(renderer, device, sampler, allocator);
+        let mut encoder = device.create_command_encoder(&wgpu::CommandEncoderDescriptor {
+            label: Some("egui-atlas-encoder"),
+        });
+        for (old, new) in moves {
+            encoder.copy_texture_to_texture(
+                image_copy(&self.pages[page].texture, [old[0], old[1]]),
+                image_copy(&new_page.texture, [new[0], new[1]]),
+                extent([old[2], old[3]]),
+            );
+        }
+        queue.submit(Some(encoder.finish()));
+        // Draws already recorded keep the old texture alive through its bind group.
+        self.pages[page] = new_page;
+        true
+    }
+
+    fn new_page(
+        &self,
+        renderer: &mut Renderer,
+        device: &Device,
+        sampler: SamplerOptions,
+        allocator: ShelfAllocator,
+    ) -> Page {
+        let texture = device.create_texture(&wgpu::TextureDescriptor {
+            label: Some("egui_atlas_texture"),
+            size: extent([self.options.page_size; 2]),
+            mip_level_count: 1,
+            sample_count: 1,
+            dimension: wgpu::TextureDimension::D2,
+            format: FORMAT,
+            usage: wgpu::TextureUsages::COPY_DST
+                | wgpu::TextureUsages::COPY_SRC
+                | wgpu::TextureUsages::TEXTURE_BINDING,
+        });
+        let view = texture.create_view(&Default::default());
+        let bind = renderer.create_texture_bind_group(device, &view, sampler, 4);
+        Page {
+            sampler,
+            texture,
+            bind: Arc::new(bind),
+            allocator,
+        }
+    }
+}
+
+/// Removes the pages for which `keep` returns `false` and moves the page
+/// indices in `entries` along with the remaining ones. Returns the number of
+/// removed pages.
+fn retain_pages<T>(
+    pages: &mut Vec<T>,
+    entries: &mut AHashMap<egui::TextureId, usize>,
+    mut keep: impl FnMut(&T) -> bool,
+) -> usize {
+    let mut new_indices = Vec::with_capacity(pages.len());
+    let mut kept = 0;
+    for page in pages.iter() {
+        new_indices.push(kept);
+        if keep(page) {
+            kept += 1;
+        }
+    }
+    let removed = pages.len() - kept;
+    if removed > 0 {
+        // `retain` visits the pages in order, like the loop above.
+        pages.retain(|page| keep(page));
+        for page in entries.values_mut() {
+            *page = new_indices[*page];
+        }
+    }
+    removed
+}
+
+fn image_copy(texture: &Texture, [x, y]: [u32; 2]) -> wgpu::ImageCopyTexture<'_> {
+    wgpu::ImageCopyTexture {
+        texture,
+        mip_level: 0,
+        origin: wgpu::Origin3d { x, y, z: 0 },
+        aspect: wgpu::TextureAspect::All,
+    }
+}
+
+fn extent([width, height]: [u32; 2]) -> wgpu::Extent3d {
+    wgpu::Extent3d {
+        width,
+        height,
+        depth_or_array_layers: 1,
+    }
+}
+
+/// Copies `size` texels at `from` in `source` to `to` in `destination` and
+/// submits the copy.
+fn copy_region(
+    device: &Device,
+    queue: &wgpu::Queue,
+    source: &Texture,
+    from: [u32; 2],
+    destination: &Texture,
+    to: [u32; 2],
+    size: [u32; 2],
+) {
+    let mut encoder = device.create_command_encoder(&wgpu::CommandEncoderDescriptor {
+        label: Some("egui-atlas-encoder"),
+    });
+    encoder.copy_texture_to_texture(
+        image_copy(source, from),
+        image_copy(destination, to),
+        extent(size),
+    );
+    queue.submit(Some(encoder.finish()));
+}
+
+/// Surrounds the RGBA image `data` of `size` with copies of its edge texels,
+/// `[left, top, right, bottom]` texels wide.
+fn pad_image(data: &[u8], size: [u32; 2], [left, top, right, bottom]: [u32; 4]) -> Vec<u8> {
+    let padded_width = size[0] + left + right;
+    let padded_height = size[1] + top + bottom;
+    let mut padded = Vec::with_capacity((padded_width * padded_height * 4) as usize);
+    for y in 0..padded_height {
+        let src_y = y.saturating_sub(top).min(size[1] - 1);
+        for x in 0..padded_width {
+            let src_x = x.saturating_sub(left).min(size[0] - 1);
+            let i = ((src_y * size[0] + src_x) * 4) as usize;
+            padded.extend_from_slice(&data[i..i + 4]);
+        }
+    }
+    padded
+}
+
+/// A row of regions of up to `height` texels.
+struct Shelf {
+    y: u32,
+    height: u32,
+    /// Left edge of the free space at the end of the shelf.
+    x: u32,
+    /// Regions allocated on the shelf. Once they are all freed, the whole
+    /// shelf is free again.
+    allocations: u32,
+}
+
+/// Packs rectangles into a `size` x `size` square, in shelves of rectangles of
+/// similar height.
+struct ShelfAllocator {
+    size: u32,
+    shelves: Vec<Shelf>,
+    /// Shelf and `[x, y, width, height]` of every region.
+    regions: AHashMap<egui::TextureId, (usize, [u32; 4])>,
+}
+
+impl ShelfAllocator {
+    fn new(size: u32) -> Self {
+        Self {
+            size,
+            shelves: Vec::new(),
+            regions: Default::default(),
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.regions.is_empty()
+    }
+
+    fn region(&self, id: egui::TextureId) -> Option<[u32; 4]> {
+        self.regions.get(&id).map(|(_, region)| *region)
+    }
+
+    /// Allocates a region of `[width, height]` for `id`, `None` if there is no
+    /// room.
+    fn allocate(&mut self, id: egui::TextureId, [width, height]: [u32; 2]) -> Option<[u32; 4]> {
+        let size = self.size;
+        let fits = |shelf: &Shelf| height <= shelf.height && shelf.x + width <= size;
+        // Prefer shelves that waste at most half their height, then a new
+        // shelf, then any shelf with room.
+        let shelf = match self
+            .shelves
+            .iter()
+            .position(|shelf| fits(shelf) && shelf.height <= 2 * height)
+        {
+            Some(shelf) => shelf,
+            None => {
+                let y = self.shelves.last().map_or(0, |shelf| shelf.y + shelf.height);
+                if y + height <= size && width <= size {
+                    self.shelves.push(Shelf {
+                        y,
+                        height,
+                        x: 0,
+                        allocations: 0,
+                    });
+                    self.shelves.len() - 1
+                } else {
+                    self.shelves.iter().position(fits)?
+                }
+            }
+        };
+        let shelf_index = shelf;
+        let shelf = &mut self.shelves[shelf_index];
+        let region = [shelf.x, shelf.y, width, height];
+        shelf.x += width;
+        shelf.allocations += 1;
+        self.regions.insert(id, (shelf_index, region));
+        Some(region)
+    }
+
+    /// Frees the region of `id` and returns it. The space is reused once all
+    /// regions of its shelf are freed.
+    fn free(&mut self, id: egui::TextureId) -> Option<[u32; 4]> {
+        let (shelf, region) = self.regions.remove(&id)?;
+        let shelf = &mut self.shelves[shelf];
+        shelf.allocations -= 1;
+        if shelf.allocations == 0 {
+            shelf.x = 0;
+        }
+        while self.shelves.last().is_some_and(|shelf| shelf.allocations == 0) {
+            self.shelves.pop();
+        }
+        Some(region)
+    }
+
+    /// Texels not covered by any region.
+    fn free_area(&self) -> u64 {
+        let used: u64 = self
+            .regions
+            .values()
+            .map(|(_, [_, _, width, height])| *width as u64 * *height as u64)
+            .sum();
+        self.size as u64 * self.size as u64 - used
+    }
+
+    /// A new allocator with all regions packed tallest first, and the regions
+    /// before and after for every id. `None` if they don't fit.
+    #[allow(clippy::type_complexity)]
+    fn compacted(&self) -> Option<(Self, Vec<([u32; 4], [u32; 4])>)> {
+        let mut regions: Vec<_> = self
+            .regions
+            .iter()
+            .map(|(id, (_, region))| (*id, *region))
+            .collect();
+        regions.sort_by_key(|(_, [_, _, width, height])| std::cmp::Reverse((*height, *width)));
+        let mut compacted = Self::new(self.size);
+        let moves = regions
+            .into_iter()
+            .map(|(id, old)| Some((old, compacted.allocate(id, [old[2], old[3]])?)))
+            .collect::<Option<_>>()?;
+        Some((compacted, moves))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const fn id(i: u64) -> egui::TextureId {
+        egui::TextureId::Managed(i)
+    }
+
+    #[test]
+    fn options_are_clamped_to_usable_pages() {
+        let options = |max_image_side, page_size| AtlasOptions {
+            max_image_side,
+            page_size,
+        };
+        assert_eq!(clamp_options(options(128, 1024), 8192), options(128, 1024));
+        assert_eq!(clamp_options(options(128, 1024), 512), options(128, 512));
+        assert_eq!(clamp_options(options(128, 100), 8192), options(98, 100));
+        // Too small to hold even a padded 1x1 image.
+        assert_eq!(clamp_options(options(128, 0), 8192), options(1, 3));
+        assert_eq!(clamp_options(options(128, 1), 8192), options(1, 3));
+    }
+
+    #[test]
+    fn shelves_reuse_freed_space() {
+        let mut allocator = ShelfAllocator::new(64);
+        assert_eq!(allocator.allocate(id(1), [40, 16]), Some([0, 0, 40, 16]));
+        assert_eq!(allocator.allocate(id(2), [20, 10]), Some([40, 0, 20, 10]));
+        // Too wide for the first shelf, and too short to waste it.
+        assert_eq!(allocator.allocate(id(3), [30, 4]), Some([0, 16, 30, 4]));
+        assert_eq!(allocator.allocate(id(4), [64, 48]), None);
+
+        allocator.free(id(3));
+        assert_eq!(allocator.allocate(id(4), [64, 48]), Some([0, 16, 64, 48]));
+        assert_eq!(allocator.free_area(), 64 * 64 - 40 * 16 - 20 * 10 - 64 * 48);
+
+        allocator.free(id(1));
+        allocator.free(id(2));
+        assert_eq!(allocator.allocate(id(5), [64, 16]), Some([0, 0, 64, 16]));
+    }
+
+    #[test]
+    fn compaction_packs_fragmented_regions() {
+        let mut allocator = ShelfAllocator::new(64);
+        for i in 0..4 {
+            allocator.allocate(id(i), [32, 16]);
+            allocator.allocate(id(10 + i), [32, 16]);
+        }
+        // Every shelf keeps one region, so nothing is reused.
+        for i in 0..4 {
+            allocator.free(id(i));
+        }
+        assert_eq!(allocator.allocate(id(20), [64, 32]), None);
+        assert_eq!(allocator.free_area(), 64 * 32);
+
+        let (mut compacted, moves) = allocator.compacted().unwrap();
+        assert_eq!(moves.len(), 4);
+        for (old, new) in &moves {
+            assert_eq!(old[2..], new[2..]);
+        }
+        assert_eq!(compacted.region(id(10)).map(|r| r[2..].to_vec()), Some(vec![32, 16]));
+        assert_eq!(compacted.allocate(id(20), [64, 32]), Some([0, 32, 64, 32]));
+    }
+
+    #[test]
+    fn empty_pages_are_removed_and_entries_reindexed() {
+        let mut pages: Vec<ShelfAllocator> = (0..4).map(|_| ShelfAllocator::new(64)).collect();
+        let mut entries = AHashMap::default();
+        for page in [1, 3] {
+            pages[page].allocate(id(page as u64), [8, 8]);
+            entries.insert(id(page as u64), page);
+        }
+        assert_eq!(retain_pages(&mut pages, &mut entries, |p| !p.is_empty()), 2);
+        assert_eq!(pages.len(), 2);
+        for (i, page) in [(1, 0), (3, 1)] {
+            assert_eq!(entries[&id(i)], page);
+            assert!(pages[page].region(id(i)).is_some());
+        }
+        assert_eq!(retain_pages(&mut pages, &mut entries, |p| !p.is_empty()), 0);
+    }
+
+    #[test]
+    fn padding_repeats_edges() {
+        // 2x1 image: red, green.
+        let data = [255, 0, 0, 255, 0, 255, 0, 255];
+        let padded = pad_image(&data, [2, 1], [1, 1, 0, 1]);
+        let red = [255, 0, 0, 255];
+        let green = [0, 255, 0, 255];
+        let row = [red, red, green].concat();
+        assert_eq!(padded, [row.clone(), row.clone(), row].concat());
+    }
+}
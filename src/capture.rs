@@ -0,0 +1,180 @@
+use std::num::NonZeroU32;
+
+use egui::{Color32, ColorImage, Rgba};
+use wgpu::{Device, Texture, TextureFormat};
+
+use crate::{waker, Error, TargetFormat};
+
+/// Creates a texture that egui can render into and that can be read back with
+/// [`read_texture`].
+pub fn create_texture(device: &Device, format: TextureFormat, width: u32, height: u32) -> Texture {
+    device.create_texture(&wgpu::TextureDescriptor {
+        label: Some("egui_capture_texture"),
+        size: wgpu::Extent3d {
+            width,
+            height,
+            depth_or_array_layers: 1,
+        },
+        mip_level_count: 1,
+        sample_count: 1,
+        dimension: wgpu::TextureDimension::D2,
+        format,
+        usage: wgpu::TextureUsages::RENDER_ATTACHMENT | wgpu::TextureUsages::COPY_SRC,
+    })
+}
+
+/// Submits `encoder` followed by a copy of `texture` into a buffer, waits for
+/// the GPU and converts the result into an sRGBA image. Fails if the buffer
+/// can't be mapped, e.g. because the device was lost.
+pub fn read_texture(
+    device: &Device,
+    queue: &wgpu::Queue,
+    mut encoder: wgpu::CommandEncoder,
+    target_format: &TargetFormat,
+    texture: &Texture,
+    [width, height]: [u32; 2],
+) -> Result<ColorImage, Error> {
+    let format = target_format.format;
+    let bytes_per_texel = format.describe().block_size as u32;
+    let unpadded_bytes_per_row = width * bytes_per_texel;
+    let align = wgpu::COPY_BYTES_PER_ROW_ALIGNMENT;
+    let padded_bytes_per_row = unpadded_bytes_per_row.div_ceil(align) * align;
+
+    let buffer = device.create_buffer(&wgpu::BufferDescriptor {
+        label: Some("egui_capture_buffer"),
+        size: (padded_bytes_per_row * height) as u64,
+        usage: wgpu::BufferUsages::COPY_DST | wgpu::BufferUsages::MAP_READ,
+        mapped_at_creation: false,
+    });
+    encoder.copy_texture_to_buffer(
+        texture.as_image_copy(),
+        wgpu::ImageCopyBuffer {
+            buffer: &buffer,
+            layout: wgpu::ImageDataLayout {
+                offset: 0,
+                bytes_per_row: NonZeroU32::new(padded_bytes_per_row),
+                rows_per_image: NonZeroU32::new(height),
+            },
+        },
+        wgpu::Extent3d {
+            width,
+            height,
+            depth_or_array_layers: 1,
+        },
+    );
+    queue.submit(Some(encoder.finish()));
+
+    let slice = buffer.slice(..);
+    waker::block_on(device, slice.map_async(wgpu::MapMode::Read))?;
+
+    let data = slice.get_mapped_range();
+    let mut pixels = Vec::with_capacity((width * height) as usize);
+    for row in data.chunks(padded_bytes_per_row as usize) {
+        for texel in row[..unpadded_bytes_per_row as usize].chunks(bytes_per_texel as usize) {
+            pixels.push(color_from_texel(format, target_format.output_scale(), texel));
+        }
+    }
+    drop(data);
+    buffer.unmap();
+
+    Ok(ColorImage {
+        size: [width as usize, height as usize],
+        pixels,
+    })
+}
+
+/// Checks that [`read_texture`] can convert texels of `format`.
+pub fn check_format(format: TextureFormat) -> Result<(), Error> {
+    match format {
+        TextureFormat::Rgba8Unorm
+        | TextureFormat::Rgba8UnormSrgb
+        | TextureFormat::Bgra8Unorm
+        | TextureFormat::Bgra8UnormSrgb
+        | TextureFormat::Rgb10a2Unorm
+        | TextureFormat::Rgba16Float
+        | TextureFormat::Rgba32Float => Ok(()),
+        _ => Err(Error::UnsupportedFormat(format)),
+    }
+}
+
+/// Converts one premultiplied texel written to a target whose
+/// [`TargetFormat::output_scale`] is `paper_white`.
+///
+/// Float targets get linear colors scaled by `paper_white`, all others get
+/// gamma-space colors, either directly or through an sRGB format.
+fn color_from_texel(format: TextureFormat, paper_white: Option<f32>, texel: &[u8]) -> Color32 {
+    let [r, g, b, a] = match format {
+        TextureFormat::Rgba8Unorm | TextureFormat::Rgba8UnormSrgb => {
+            return Color32::from_rgba_premultiplied(texel[0], texel[1], texel[2], texel[3]);
+        }
+        TextureFormat::Bgra8Unorm | TextureFormat::Bgra8UnormSrgb => {
+            return Color32::from_rgba_premultiplied(texel[2], texel[1], texel[0], texel[3]);
+        }
+        TextureFormat::Rgb10a2Unorm => {
+            let bits = u32::from_le_bytes([texel[0], texel[1], texel[2], texel[3]]);
+            let unorm = |shift: u32, max: u32| ((bits >> shift) & max) as f32 / max as f32;
+            [
+                unorm(0, 1023),
+                unorm(10, 1023),
+                unorm(20, 1023),
+                unorm(30, 3),
+            ]
+        }
+        TextureFormat::Rgba16Float => {
+            let half = |i: usize| f32_from_f16(u16::from_le_bytes([texel[i], texel[i + 1]]));
+            [half(0), half(2), half(4), half(6)]
+        }
+        TextureFormat::Rgba32Float => {
+            let float = |i: usize| {
+                f32::from_le_bytes([texel[i], texel[i + 1], texel[i + 2], texel[i + 3]])
+            };
+            [float(0), float(4), float(8), float(12)]
+        }
+        _ => unreachable!("{:?} passed check_format", format),
+    };
+    match paper_white {
+        Some(paper_white) => {
+            Rgba::from_rgba_premultiplied(r / paper_white, g / paper_white, b / paper_white, a)
+                .into()
+        }
+        None => {
+            let u8 = |v: f32| (v.clamp(0.0, 1.0) * 255.0).round() as u8;
+            Color32::from_rgba_premultiplied(u8(r), u8(g), u8(b), u8(a))
+        }
+    }
+}
+
+fn f32_from_f16(bits: u16) -> f32 {
+    let sign = if bits & 0x8000 != 0 { -1.0 } else { 1.0 };
+    let exponent = ((bits >> 10) & 0x1f) as i32;
+    let mantissa = (bits & 0x3ff) as f32;
+    match exponent {
+        0 => sign * mantissa * 2f32.powi(-24),
+        31 if mantissa == 0.0 => sign * f32::INFINITY,
+        31 => f32::NAN,
+        _ => sign * (1.0 + mantissa / 1024.0) * 2f32.powi(exponent - 15),
+    }
+}
+
+/// Encodes `image` as a PNG file with unmultiplied alpha.
+#[cfg(feature = "png")]
+pub fn encode_png(image: &ColorImage) -> Result<Vec<u8>, png::EncodingError> {
+    let mut bytes = Vec::new();
+    let mut encoder = png::Encoder::new(&mut bytes, image.size[0] as u32, image.size[1] as u32);
+    encoder.set_color(png::ColorType::Rgba);
+    encoder.set_depth(png::BitDepth::Eight);
+    encoder
+        .write_header()?
+        .write_image_data(&srgba_unmultiplied(image))?;
+    Ok(bytes)
+}
+
+/// The pixels of `image` as unmultiplied sRGBA bytes, as stored in a PNG file.
+#[cfg(feature = "png")]
+pub(crate) fn srgba_unmultiplied(image: &ColorImage) -> Vec<u8> {
+    image
+        .pixels
+        .iter()
+        .flat_map(|color| color.to_srgba_unmultiplied())
+        .collect()
+}
@@ -0,0 +1,33 @@
+use egui::TextureId;
+use wgpu::TextureFormat;
+
+/// Why egui couldn't be painted.
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    /// A mesh uses a texture that was never set or already freed, with
+    /// [`crate::MissingTexturePolicy::Error`].
+    #[error("no texture with id {0:?}")]
+    MissingTexture(TextureId),
+    /// An image's pixel count doesn't match its size, or a partial update
+    /// doesn't fit into the texture it updates or has a different format.
+    #[error("image of size {size:?} for texture {id:?} {reason}")]
+    TextureSizeMismatch {
+        id: TextureId,
+        size: [usize; 2],
+        reason: String,
+    },
+    /// The format can't be rendered to, not with the requested sample count, or
+    /// isn't a depth format where one is expected.
+    #[error("rendering to {0:?} is not supported")]
+    UnsupportedFormat(TextureFormat),
+    /// A texture is larger than the device's `max_texture_dimension_2d`.
+    #[error("texture {id:?} of size {size:?} exceeds the maximum texture side of {max_side}")]
+    TextureTooLarge {
+        id: TextureId,
+        size: [usize; 2],
+        max_side: u32,
+    },
+    /// The buffer a capture is copied into couldn't be mapped for reading.
+    #[error("failed to read back the captured frame: {0}")]
+    CaptureReadback(#[from] wgpu::BufferAsyncError),
+}
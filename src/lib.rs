@@ -1,18 +1,72 @@
+/// Enters a `tracing` span named `$name` until the end of the enclosing
+/// block, if the `tracing` feature is enabled.
+macro_rules! span {
+    ($name:literal) => {
+        #[cfg(feature = "tracing")]
+        let _span = tracing::info_span!($name).entered();
+    };
+}
+
+mod arena;
+mod atlas;
+mod capture;
+mod error;
+mod mipmap;
 mod painter;
 mod pipeline;
+#[cfg(feature = "snapshot")]
+pub mod snapshot;
+pub mod software;
+mod timer;
+mod upload;
+mod waker;
+use std::{cell::RefCell, collections::VecDeque, rc::Rc};
+
 use painter::Painter;
-pub use pipeline::Pipeline;
+#[cfg(feature = "png")]
+pub use capture::encode_png;
+pub use atlas::AtlasOptions;
+pub use error::Error;
+pub use painter::{MissingTexturePolicy, PaintStats, Renderer, SamplerOptions, TextureOptions};
+pub use pipeline::TargetFormat;
+pub use upload::BufferUpload;
 use wgpu::{Adapter, Device, TextureView};
+
+/// Number of frames kept in [`EguiWgpu::paint_stats_history`].
+pub const PAINT_STATS_HISTORY_LEN: usize = 120;
+
+/// egui for one window.
+///
+/// Additional windows on the same device can share the [`Renderer`] of the
+/// first one, see [`EguiWgpu::with_renderer`], so native textures registered
+/// through any of them can be shown in all of them.
 pub struct EguiWgpu {
     pub egui_ctx: egui::Context,
-    pub egui_winit: egui_winit::State,
+    /// `None` for instances created with [`EguiWgpu::new_headless`].
+    pub egui_winit: Option<egui_winit::State>,
+    renderer: Rc<RefCell<Renderer>>,
     painter: painter::Painter,
     shapes: Vec<egui::epaint::ClippedShape>,
     textures_delta: egui::TexturesDelta,
+    /// Used when the [`egui::RawInput`] passed to [`EguiWgpu::run_with_input`] leaves them unset.
+    max_texture_side: usize,
+    pixels_per_point: f32,
+    paint_stats_history: VecDeque<PaintStats>,
 }
 
 pub struct RenderTarget<'a> {
+    /// The attachment egui draws into, see [`TargetFormat::create_msaa_texture`]
+    /// for multisampling.
     pub view: &'a TextureView,
+    /// Single-sampled view that a multisampled `view` is resolved into,
+    /// e.g. the surface texture. `None` when `view` is not multisampled.
+    pub resolve_target: Option<&'a TextureView>,
+    /// Depth attachment of [`TargetFormat::depth_format`], for drawing egui in
+    /// a render pass shared with a scene using depth. Only read, never written.
+    pub depth_view: Option<&'a TextureView>,
+    /// Format of `view` and `depth_view`. A pipeline is created for every new
+    /// format on first use.
+    pub format: TargetFormat,
     pub clear_color: Option<wgpu::Color>,
     pub width: u32,
     pub height: u32,
@@ -22,73 +76,374 @@ impl EguiWgpu {
         adapter: &Adapter,
         device: &Device,
         window: &egui_winit::winit::window::Window,
+    ) -> Self {
+        Self::with_renderer(Rc::new(RefCell::new(Renderer::new(device))), adapter, window)
+    }
+
+    /// Creates an instance for another window, sharing `renderer` with the
+    /// instances it was taken from with [`Self::renderer`].
+    ///
+    /// All instances sharing a renderer must paint with the same device.
+    pub fn with_renderer(
+        renderer: Rc<RefCell<Renderer>>,
+        adapter: &Adapter,
+        window: &egui_winit::winit::window::Window,
     ) -> Self {
         let max_texture_side = adapter.limits().max_texture_dimension_2d as usize;
+        let egui_winit = egui_winit::State::new(max_texture_side, window);
         Self {
             egui_ctx: egui::Context::default(),
-            egui_winit: egui_winit::State::new(max_texture_side, window),
-            painter: Painter::new(device),
+            pixels_per_point: egui_winit.pixels_per_point(),
+            egui_winit: Some(egui_winit),
+            renderer,
+            painter: Painter::new(),
             shapes: Default::default(),
             textures_delta: Default::default(),
+            max_texture_side,
+            paint_stats_history: Default::default(),
         }
     }
 
+    /// Creates an instance without a window, for offscreen rendering or tests.
+    ///
+    /// Drive it with [`Self::run_with_input`]; [`Self::run`] and
+    /// [`Self::on_event`] need a window.
+    pub fn new_headless(device: &Device, max_texture_side: usize, pixels_per_point: f32) -> Self {
+        Self {
+            egui_ctx: egui::Context::default(),
+            egui_winit: None,
+            renderer: Rc::new(RefCell::new(Renderer::new(device))),
+            painter: Painter::new(),
+            shapes: Default::default(),
+            textures_delta: Default::default(),
+            max_texture_side,
+            pixels_per_point,
+            paint_stats_history: Default::default(),
+        }
+    }
+
+    /// Uploads vertex, index and uniform data with `upload` instead of
+    /// [`BufferUpload::WriteBuffer`], e.g.
+    /// `EguiWgpu::new(&adapter, &device, &window).with_buffer_upload(BufferUpload::StagingBelt)`.
+    pub fn with_buffer_upload(mut self, upload: BufferUpload) -> Self {
+        self.painter.set_buffer_upload(upload);
+        self
+    }
+
+    /// Packs egui managed images up to [`AtlasOptions::max_image_side`] into
+    /// shared textures, so that meshes showing different small images, like
+    /// icons, can be drawn together.
+    ///
+    /// Images with mipmaps or wrapping other than
+    /// [`wgpu::AddressMode::ClampToEdge`] keep a texture of their own.
+    pub fn with_texture_atlas(mut self, device: &Device, options: AtlasOptions) -> Self {
+        self.painter.set_texture_atlas(device, options);
+        self
+    }
+
+    /// The GPU state shared with other windows, to pass to [`Self::with_renderer`].
+    pub fn renderer(&self) -> &Rc<RefCell<Renderer>> {
+        &self.renderer
+    }
+
     /// Returns `true` if egui wants exclusive use of this event
     /// (e.g. a mouse click on an egui window, or entering text into a text field).
     /// For instance, if you use egui for a game, you want to first call this
     /// and only when this returns `false` pass on the events to your game.
     ///
     /// Note that egui uses `tab` to move focus between elements, so this will always return `true` for tabs.
+    ///
+    /// Always returns `false` for headless instances.
     pub fn on_event(&mut self, event: &egui_winit::winit::event::WindowEvent<'_>) -> bool {
-        self.egui_winit.on_event(&self.egui_ctx, event)
+        match &mut self.egui_winit {
+            Some(egui_winit) => egui_winit.on_event(&self.egui_ctx, event),
+            None => false,
+        }
     }
 
     /// Returns `true` if egui requests a repaint.
     ///
     /// Call [`Self::paint`] later to paint.
+    ///
+    /// # Panics
+    /// For headless instances, use [`Self::run_with_input`] instead.
     pub fn run(
         &mut self,
         window: &egui_winit::winit::window::Window,
         run_ui: impl FnOnce(&egui::Context),
     ) -> bool {
-        let raw_input = self.egui_winit.take_egui_input(window);
+        let egui_winit = self
+            .egui_winit
+            .as_mut()
+            .expect("EguiWgpu::run needs a window, use run_with_input for headless instances");
+        let raw_input = egui_winit.take_egui_input(window);
+        let (needs_repaint, platform_output) = self.run_with_input(raw_input, run_ui);
+        if let Some(egui_winit) = &mut self.egui_winit {
+            egui_winit.handle_platform_output(window, &self.egui_ctx, platform_output);
+        }
+        needs_repaint
+    }
+
+    /// Runs egui on `raw_input` instead of input gathered from a window.
+    ///
+    /// `pixels_per_point` and `max_texture_side` default to the values given on
+    /// construction when unset. Returns whether egui requests a repaint, and the
+    /// platform output (cursor, clipboard, opened urls...) for the caller to handle.
+    ///
+    /// Call [`Self::paint`] later to paint.
+    pub fn run_with_input(
+        &mut self,
+        mut raw_input: egui::RawInput,
+        run_ui: impl FnOnce(&egui::Context),
+    ) -> (bool, egui::PlatformOutput) {
+        raw_input.pixels_per_point.get_or_insert(self.pixels_per_point);
+        raw_input.max_texture_side.get_or_insert(self.max_texture_side);
         let egui::FullOutput {
             platform_output,
             needs_repaint,
             textures_delta,
             shapes,
         } = self.egui_ctx.run(raw_input, run_ui);
-        self.egui_winit.handle_platform_output(
-            window,
-            &self.egui_ctx,
-            platform_output,
-        );
 
         self.shapes = shapes;
         self.textures_delta.append(textures_delta);
 
-        needs_repaint
+        (needs_repaint, platform_output)
     }
 
-    /// Paint the results of the last call to [`Self::run`].
+    /// Paint the results of the last call to [`Self::run`], returning what it
+    /// took.
     pub fn paint(
         &mut self,
         device: &Device,
         queue: &wgpu::Queue,
-        pipeline: &Pipeline,
         target: RenderTarget,
-    ) {
+    ) -> Result<PaintStats, Error> {
         let shapes = std::mem::take(&mut self.shapes);
         let textures_delta = std::mem::take(&mut self.textures_delta);
-        let clipped_meshes = self.egui_ctx.tessellate(shapes);
-        self.painter.paint_and_update_textures(
+        let clipped_meshes = self.tessellate(shapes);
+        let stats = self.painter.paint_and_update_textures(
+            &mut self.renderer.borrow_mut(),
             device,
             queue,
-            pipeline,
             target,
             self.egui_ctx.pixels_per_point(),
             clipped_meshes,
             &textures_delta,
-        );
+        )?;
+        Ok(self.record_paint_stats(stats))
+    }
+
+    /// Like [`Self::paint`], but records the egui render pass into `encoder`
+    /// instead of submitting it, so it can be batched with the rest of a frame.
+    ///
+    /// Textures are still uploaded through `queue`, so the caller must submit
+    /// `encoder` on the same queue, before the next call to `paint_into`.
+    pub fn paint_into(
+        &mut self,
+        device: &Device,
+        queue: &wgpu::Queue,
+        encoder: &mut wgpu::CommandEncoder,
+        target: RenderTarget,
+    ) -> Result<PaintStats, Error> {
+        let shapes = std::mem::take(&mut self.shapes);
+        let textures_delta = std::mem::take(&mut self.textures_delta);
+        let clipped_meshes = self.tessellate(shapes);
+        let stats = self.painter.paint_and_update_textures_into(
+            &mut self.renderer.borrow_mut(),
+            device,
+            queue,
+            encoder,
+            target,
+            self.egui_ctx.pixels_per_point(),
+            clipped_meshes,
+            &textures_delta,
+        )?;
+        Ok(self.record_paint_stats(stats))
+    }
+
+    /// Uploads the results of the last call to [`Self::run`] without drawing
+    /// them. Call [`Self::render`] afterwards to draw into an open render pass.
+    ///
+    /// `target_format`, `width` and `height` describe the target that the
+    /// render pass will draw to, `width` and `height` in physical pixels.
+    pub fn prepare(
+        &mut self,
+        device: &Device,
+        queue: &wgpu::Queue,
+        target_format: &TargetFormat,
+        width: u32,
+        height: u32,
+    ) -> Result<PaintStats, Error> {
+        let shapes = std::mem::take(&mut self.shapes);
+        let textures_delta = std::mem::take(&mut self.textures_delta);
+        let clipped_meshes = self.tessellate(shapes);
+        let stats = self.painter.prepare(
+            &mut self.renderer.borrow_mut(),
+            device,
+            queue,
+            target_format,
+            width,
+            height,
+            self.egui_ctx.pixels_per_point(),
+            &clipped_meshes,
+            &textures_delta,
+        )?;
+        Ok(self.record_paint_stats(stats))
+    }
+
+    /// Statistics of the last [`PAINT_STATS_HISTORY_LEN`] frames painted with
+    /// [`Self::paint`], [`Self::paint_into`] or [`Self::prepare`], oldest first.
+    pub fn paint_stats_history(&self) -> &VecDeque<PaintStats> {
+        &self.paint_stats_history
+    }
+
+    /// GPU time of the egui render pass of a recent frame painted with
+    /// [`Self::paint`] or [`Self::paint_into`], in milliseconds.
+    ///
+    /// Timings are read back a few frames late to avoid waiting for the GPU.
+    /// `None` until the first one arrives, and always `None` if the device
+    /// wasn't created with [`wgpu::Features::TIMESTAMP_QUERY`]. Frames drawn
+    /// with [`Self::prepare`] and [`Self::render`] aren't timed.
+    pub fn gpu_time_ms(&self) -> Option<f32> {
+        self.painter.gpu_time_ms()
+    }
+
+    fn record_paint_stats(&mut self, stats: PaintStats) -> PaintStats {
+        if self.paint_stats_history.len() == PAINT_STATS_HISTORY_LEN {
+            self.paint_stats_history.pop_front();
+        }
+        self.paint_stats_history.push_back(stats);
+        stats
+    }
+
+    /// Draws what the last call to [`Self::prepare`] uploaded into `rpass`,
+    /// which must draw to a target of the format passed to `prepare`.
+    pub fn render<'rp>(&'rp self, rpass: &mut wgpu::RenderPass<'rp>) {
+        self.painter.render(rpass);
+    }
+
+    /// Sets what to do with meshes whose texture doesn't exist. Skips them by
+    /// default.
+    pub fn set_missing_texture_policy(&mut self, policy: MissingTexturePolicy) {
+        self.painter.missing_texture_policy = policy;
+    }
+
+    /// Makes `view` drawable by egui, e.g. to show a render target in an
+    /// [`egui::Image`].
+    ///
+    /// The view must be a filterable 2D float texture. Call
+    /// [`Self::free_native_texture`] once it is no longer needed. The id is
+    /// valid in every window sharing this instance's [`Renderer`].
+    pub fn register_native_texture(
+        &mut self,
+        device: &Device,
+        view: &TextureView,
+        options: TextureOptions,
+    ) -> egui::TextureId {
+        self.renderer
+            .borrow_mut()
+            .register_native_texture(device, view, options)
+    }
+
+    /// Replaces the texture shown for `id` with `view`, e.g. after a resize.
+    pub fn update_native_texture(
+        &mut self,
+        device: &Device,
+        id: egui::TextureId,
+        view: &TextureView,
+        options: TextureOptions,
+    ) {
+        self.renderer
+            .borrow_mut()
+            .update_native_texture(device, id, view, options);
+    }
+
+    /// Frees a texture registered with [`Self::register_native_texture`].
+    pub fn free_native_texture(&mut self, id: egui::TextureId) {
+        self.renderer.borrow_mut().free_native_texture(id);
+    }
+
+    /// Sets how the egui managed texture `id` is stored and sampled, e.g. the id
+    /// of a [`egui::TextureHandle`] returned by [`egui::Context::load_texture`].
+    ///
+    /// Call it right after loading the texture for [`TextureOptions::mipmaps`]
+    /// to apply to its first upload.
+    pub fn set_texture_options(
+        &mut self,
+        device: &Device,
+        id: egui::TextureId,
+        options: TextureOptions,
+    ) {
+        self.painter
+            .set_texture_options(&mut self.renderer.borrow_mut(), device, id, options);
+    }
+
+    fn tessellate(&self, shapes: Vec<egui::epaint::ClippedShape>) -> Vec<egui::ClippedMesh> {
+        span!("egui_wgpu::tessellate");
+        self.egui_ctx.tessellate(shapes)
+    }
+
+    /// Renders the results of the last call to [`Self::run`] offscreen and reads
+    /// them back as an sRGBA image, e.g. for bug reports or visual tests.
+    ///
+    /// Unlike [`Self::paint`] this does not consume the output, so call it
+    /// before `paint` to capture what is about to be shown. Blocks until the GPU
+    /// has finished rendering.
+    ///
+    /// The [`TargetFormat::depth_format`] of `target_format` is ignored.
+    pub fn capture(
+        &mut self,
+        device: &Device,
+        queue: &wgpu::Queue,
+        target_format: TargetFormat,
+        width: u32,
+        height: u32,
+    ) -> Result<egui::ColorImage, Error> {
+        let target_format = TargetFormat {
+            depth_format: None,
+            ..target_format
+        };
+        capture::check_format(target_format.format)?;
+        let texture = capture::create_texture(device, target_format.format, width, height);
+        let view = texture.create_view(&Default::default());
+        let msaa_texture = (target_format.sample_count > 1)
+            .then(|| target_format.create_msaa_texture(device, width, height));
+        let msaa_view = msaa_texture.map(|t| t.create_view(&Default::default()));
+        let target = RenderTarget {
+            view: msaa_view.as_ref().unwrap_or(&view),
+            resolve_target: msaa_view.as_ref().map(|_| &view),
+            depth_view: None,
+            format: target_format,
+            clear_color: Some(wgpu::Color::TRANSPARENT),
+            width,
+            height,
+        };
+
+        let mut encoder = device.create_command_encoder(&wgpu::CommandEncoderDescriptor {
+            label: Some("egui-capture-encoder"),
+        });
+        let clipped_meshes = self.tessellate(self.shapes.clone());
+        self.painter.paint_and_update_textures_into(
+            &mut self.renderer.borrow_mut(),
+            device,
+            queue,
+            &mut encoder,
+            target,
+            self.egui_ctx.pixels_per_point(),
+            clipped_meshes,
+            &self.textures_delta,
+        )?;
+        // Only consumed once uploaded, so a failed capture leaves it for the
+        // next paint.
+        self.textures_delta.clear();
+        capture::read_texture(
+            device,
+            queue,
+            encoder,
+            &target_format,
+            &texture,
+            [width, height],
+        )
     }
 }
@@ -0,0 +1,198 @@
+use std::{borrow::Cow, num::NonZeroU32};
+
+use ahash::AHashMap;
+use wgpu::{BindGroupLayout, Device, RenderPipeline, ShaderModule, Texture, TextureFormat};
+
+/// Number of mip levels of a full chain for a `width` x `height` texture.
+pub fn mip_level_count(width: u32, height: u32) -> u32 {
+    32 - width.max(height).max(1).leading_zeros()
+}
+
+/// Fills the mip chain of a texture from its first level with linear
+/// downsampling render passes.
+pub struct MipmapGenerator {
+    module: ShaderModule,
+    bind_group_layout: BindGroupLayout,
+    sampler: wgpu::Sampler,
+    pipelines: AHashMap<TextureFormat, RenderPipeline>,
+}
+
+impl MipmapGenerator {
+    pub fn new(device: &Device) -> Self {
+        let module = device.create_shader_module(&wgpu::ShaderModuleDescriptor {
+            label: Some("shader/blit.wgsl"),
+            source: wgpu::ShaderSource::Wgsl(Cow::Borrowed(include_str!("shader/blit.wgsl"))),
+        });
+        let bind_group_layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            label: Some("egui_mipmap_bind_group_layout"),
+            entries: &[
+                wgpu::BindGroupLayoutEntry {
+                    binding: 0,
+                    visibility: wgpu::ShaderStages::FRAGMENT,
+                    ty: wgpu::BindingType::Texture {
+                        multisampled: false,
+                        sample_type: wgpu::TextureSampleType::Float { filterable: true },
+                        view_dimension: wgpu::TextureViewDimension::D2,
+                    },
+                    count: None,
+                },
+                wgpu::BindGroupLayoutEntry {
+                    binding: 1,
+                    visibility: wgpu::ShaderStages::FRAGMENT,
+                    ty: wgpu::BindingType::Sampler(wgpu::SamplerBindingType::Filtering),
+                    count: None,
+                },
+            ],
+        });
+        let sampler = device.create_sampler(&wgpu::SamplerDescriptor {
+            label: Some("egui_mipmap_sampler"),
+            mag_filter: wgpu::FilterMode::Linear,
+            min_filter: wgpu::FilterMode::Linear,
+            ..Default::default()
+        });
+        Self {
+            module,
+            bind_group_layout,
+            sampler,
+            pipelines: Default::default(),
+        }
+    }
+
+    /// Regenerates levels `1..mip_level_count` of `texture` from level 0.
+    ///
+    /// Only the part of each level covering `region` (`[x, y, width, height]`
+    /// in level 0 texels) is redrawn. `texture` needs the `TEXTURE_BINDING`
+    /// and `RENDER_ATTACHMENT` usages.
+    #[allow(clippy::too_many_arguments)]
+    pub fn generate(
+        &mut self,
+        device: &Device,
+        encoder: &mut wgpu::CommandEncoder,
+        texture: &Texture,
+        format: TextureFormat,
+        size: [u32; 2],
+        mip_level_count: u32,
+        region: [u32; 4],
+    ) {
+        let Self {
+            module,
+            bind_group_layout,
+            sampler,
+            pipelines,
+        } = self;
+        let pipeline = pipelines
+            .entry(format)
+            .or_insert_with(|| create_pipeline(device, module, bind_group_layout, format));
+
+        let views: Vec<_> = (0..mip_level_count)
+            .map(|level| {
+                texture.create_view(&wgpu::TextureViewDescriptor {
+                    label: None,
+                    base_mip_level: level,
+                    mip_level_count: NonZeroU32::new(1),
+                    ..Default::default()
+                })
+            })
+            .collect();
+
+        let [x, y, width, height] = region;
+        for level in 1..mip_level_count {
+            let level_width = (size[0] >> level).max(1);
+            let level_height = (size[1] >> level).max(1);
+            // Texels of this level touched by `region`, rounded outwards.
+            let min_x = (x >> level).min(level_width - 1);
+            let min_y = (y >> level).min(level_height - 1);
+            let max_x = ((x + width + (1 << level) - 1) >> level).clamp(min_x + 1, level_width);
+            let max_y = ((y + height + (1 << level) - 1) >> level).clamp(min_y + 1, level_height);
+
+            let bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+                label: None,
+                layout: bind_group_layout,
+                entries: &[
+                    wgpu::BindGroupEntry {
+                        binding: 0,
+                        resource: wgpu::BindingResource::TextureView(
+                            &views[level as usize - 1],
+                        ),
+                    },
+                    wgpu::BindGroupEntry {
+                        binding: 1,
+                        resource: wgpu::BindingResource::Sampler(sampler),
+                    },
+                ],
+            });
+
+            let mut rpass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+                label: Some("egui-mipmap-rpass"),
+                color_attachments: &[wgpu::RenderPassColorAttachment {
+                    view: &views[level as usize],
+                    resolve_target: None,
+                    ops: wgpu::Operations {
+                        load: wgpu::LoadOp::Load,
+                        store: true,
+                    },
+                }],
+                depth_stencil_attachment: None,
+            });
+            rpass.set_pipeline(pipeline);
+            rpass.set_bind_group(0, &bind_group, &[]);
+            rpass.set_scissor_rect(min_x, min_y, max_x - min_x, max_y - min_y);
+            rpass.draw(0..3, 0..1);
+        }
+    }
+}
+
+fn create_pipeline(
+    device: &Device,
+    module: &ShaderModule,
+    bind_group_layout: &BindGroupLayout,
+    format: TextureFormat,
+) -> RenderPipeline {
+    let pipeline_layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+        label: Some("egui_mipmap_pipeline_layout"),
+        bind_group_layouts: &[bind_group_layout],
+        push_constant_ranges: &[],
+    });
+    device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
+        label: Some("egui_mipmap_pipeline"),
+        layout: Some(&pipeline_layout),
+        vertex: wgpu::VertexState {
+            module,
+            entry_point: "vs_main",
+            buffers: &[],
+        },
+        primitive: wgpu::PrimitiveState::default(),
+        depth_stencil: None,
+        multisample: wgpu::MultisampleState::default(),
+        fragment: Some(wgpu::FragmentState {
+            module,
+            entry_point: "fs_main",
+            targets: &[format.into()],
+        }),
+        multiview: None,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn blit_shader_is_valid() {
+        let module = naga::front::wgsl::parse_str(include_str!("shader/blit.wgsl")).unwrap();
+        naga::valid::Validator::new(
+            naga::valid::ValidationFlags::all(),
+            naga::valid::Capabilities::empty(),
+        )
+        .validate(&module)
+        .unwrap();
+    }
+
+    #[test]
+    fn full_mip_chain_length() {
+        assert_eq!(mip_level_count(1, 1), 1);
+        assert_eq!(mip_level_count(2, 1), 2);
+        assert_eq!(mip_level_count(256, 256), 9);
+        assert_eq!(mip_level_count(300, 20), 9);
+    }
+}
@@ -1,92 +1,601 @@
 use ahash::AHashMap;
 use egui::ClippedMesh;
-use std::num::{NonZeroU32, NonZeroU64};
-use wgpu::{util::DeviceExt, BindGroup, BufferBinding, BufferUsages, Device, Texture};
+use std::{
+    num::{NonZeroU32, NonZeroU64},
+    ops::Range,
+    sync::Arc,
+};
+use wgpu::{util::DeviceExt, BindGroup, BufferBinding, BufferUsages, Device, Texture, TextureView};
 
 use crate::{
-    pipeline::{Pipeline, SizedBuffer, UniformBufferData},
-    RenderTarget,
+    arena::BufferArena,
+    atlas::{self, Atlas, AtlasOptions},
+    mipmap::{self, MipmapGenerator},
+    pipeline::{PipelineCache, TargetFormat, UniformBufferData},
+    timer::GpuTimer,
+    upload::{BufferUpload, Uploader},
+    Error, RenderTarget,
 };
 
 pub struct TextureBind {
-    bind: BindGroup,
+    /// Shared with the [`Draw`]s recorded from it, so a texture that is freed or
+    /// replaced can still be drawn by the following [`Painter::render`].
+    bind: Arc<BindGroup>,
+    /// `None` for native textures, which are owned by the caller.
+    texture: Option<ManagedTexture>,
+    comps: u32,
+}
+
+/// A texture created by the painter from an [`egui::epaint::ImageDelta`].
+struct ManagedTexture {
     texture: Texture,
+    view: TextureView,
+    format: wgpu::TextureFormat,
+    size: [u32; 2],
+    mip_level_count: u32,
 }
 
-pub struct Painter {
-    sampler: wgpu::Sampler,
+/// How a texture is stored and sampled, see [`Renderer::register_native_texture`]
+/// and [`crate::EguiWgpu::set_texture_options`].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub struct TextureOptions {
+    /// The texture holds coverage in its red channel, like egui's font atlas,
+    /// instead of premultiplied sRGBA colors.
+    ///
+    /// Ignored for egui managed textures, whose format is known.
+    pub alpha_only: bool,
+    /// Allocate a full mip chain and generate it on the GPU whenever the
+    /// texture is uploaded or partially updated.
+    ///
+    /// Ignored for native textures, which bring their own mip chain.
+    pub mipmaps: bool,
+    pub sampler: SamplerOptions,
+}
+
+/// Filtering and wrapping of a texture. The painter shares one [`wgpu::Sampler`]
+/// between all textures with equal options.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct SamplerOptions {
+    /// Filter used when a texel covers more than one pixel.
+    pub magnification: wgpu::FilterMode,
+    /// Filter used when a texel covers less than one pixel.
+    pub minification: wgpu::FilterMode,
+    /// Filter used between mip levels, only relevant for textures with mipmaps.
+    pub mipmap_filter: wgpu::FilterMode,
+    /// How texture coordinates outside `0..=1` are handled, in both directions.
+    pub wrap_mode: wgpu::AddressMode,
+}
+
+impl Default for SamplerOptions {
+    fn default() -> Self {
+        Self {
+            magnification: wgpu::FilterMode::Nearest,
+            minification: wgpu::FilterMode::Nearest,
+            mipmap_filter: wgpu::FilterMode::Nearest,
+            wrap_mode: wgpu::AddressMode::ClampToEdge,
+        }
+    }
+}
+
+impl SamplerOptions {
+    pub const LINEAR: Self = Self {
+        magnification: wgpu::FilterMode::Linear,
+        minification: wgpu::FilterMode::Linear,
+        mipmap_filter: wgpu::FilterMode::Linear,
+        wrap_mode: wgpu::AddressMode::ClampToEdge,
+    };
+}
+
+/// What to do with meshes whose texture doesn't exist, e.g. because it was
+/// freed too early.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum MissingTexturePolicy {
+    /// Don't draw the mesh.
+    #[default]
+    Skip,
+    /// Draw the mesh with a magenta texture, to make the problem visible.
+    Placeholder,
+    /// Fail with [`Error::MissingTexture`] and draw nothing.
+    Error,
+}
+
+/// What painting one frame did, returned by [`crate::EguiWgpu::paint`].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct PaintStats {
+    /// Meshes egui tessellated the frame into.
+    pub clipped_meshes: usize,
+    /// `draw_indexed` calls recorded into the render pass.
+    pub draw_calls: usize,
+    /// Draw calls that would have been recorded without batching, one per
+    /// drawn mesh.
+    pub unbatched_draw_calls: usize,
+    /// Meshes not drawn because their clip rect is empty or outside the
+    /// target, their vertices lie outside the clip rect, or their texture is
+    /// missing. Their vertices and indices aren't uploaded.
+    pub skipped_meshes: usize,
+    /// Vertices uploaded, not counting skipped meshes.
+    pub vertices: usize,
+    /// Indices uploaded, not counting skipped meshes.
+    pub indices: usize,
+    /// Bytes written to vertex, index and uniform buffers.
+    pub buffer_bytes_written: u64,
+    /// Vertex, index and uniform buffers created because there was none yet,
+    /// or reallocated to grow or shrink.
+    pub buffers_allocated: usize,
+    /// Bytes of egui managed texture data uploaded.
+    pub texture_bytes_uploaded: u64,
+    /// Size of all egui managed textures of this window after the frame,
+    /// including their mip chains. Native textures aren't counted.
+    pub resident_texture_bytes: u64,
+}
+
+/// A batch of meshes uploaded by [`Painter::prepare`], waiting to be drawn by
+/// [`Painter::render`] with one `draw_indexed` call.
+struct Draw {
+    /// `[x, y, width, height]` in physical pixels.
+    scissor: [u32; 4],
+    bind: Arc<BindGroup>,
+    /// `Uint16` if all vertices of the batch can be indexed with 16 bits.
+    index_format: wgpu::IndexFormat,
+    /// Range of the batch's indices in the region of `index_format` of the
+    /// index arena.
+    indices: Range<u32>,
+    /// Offset of the batch's first vertex in the vertex arena. Indices of
+    /// later meshes in the batch are rebased onto it.
+    base_vertex: u32,
+}
+
+/// GPU state shared by every window drawing on one device: render pipelines,
+/// samplers, the mipmap generator and native textures, which can be shown in
+/// any window.
+///
+/// egui managed textures such as the font atlas belong to one
+/// [`egui::Context`], so they live in the [`crate::EguiWgpu`] of that window.
+pub struct Renderer {
+    pipelines: PipelineCache,
+    samplers: AHashMap<SamplerOptions, wgpu::Sampler>,
+    mipmap_generator: MipmapGenerator,
+    /// Native textures, keyed by [`egui::TextureId::User`].
     textures: AHashMap<egui::TextureId, TextureBind>,
-    vertex_buffers: Vec<SizedBuffer>,
-    index_buffers: Vec<SizedBuffer>,
-    #[cfg(feature = "epi")]
     /// [`egui::TextureId::User`] index
     next_native_tex_id: u64,
+    /// Drawn for missing textures with [`MissingTexturePolicy::Placeholder`].
+    placeholder: Option<Arc<BindGroup>>,
 }
 
-impl Painter {
+impl Renderer {
     pub fn new(device: &Device) -> Self {
-        let sampler = device.create_sampler(&wgpu::SamplerDescriptor {
-            label: None,
-            ..Default::default()
-        });
+        let mut samplers = AHashMap::default();
+        let options = SamplerOptions::default();
+        samplers.insert(options, create_sampler(device, options));
         Self {
-            sampler,
+            pipelines: PipelineCache::new(device),
+            samplers,
+            mipmap_generator: MipmapGenerator::new(device),
             textures: Default::default(),
-            #[cfg(feature = "epi")]
             next_native_tex_id: 0,
-            vertex_buffers: Default::default(),
-            index_buffers: Default::default(),
+            placeholder: None,
+        }
+    }
+
+    /// Makes `view` drawable by egui, e.g. in an [`egui::Image`].
+    ///
+    /// The view must be a filterable 2D float texture. Call
+    /// [`Self::free_native_texture`] once it is no longer needed.
+    pub fn register_native_texture(
+        &mut self,
+        device: &Device,
+        view: &TextureView,
+        options: TextureOptions,
+    ) -> egui::TextureId {
+        let id = egui::TextureId::User(self.next_native_tex_id);
+        self.next_native_tex_id += 1;
+        self.update_native_texture(device, id, view, options);
+        id
+    }
+
+    /// Replaces the texture shown for `id` with `view`, e.g. after a resize.
+    pub fn update_native_texture(
+        &mut self,
+        device: &Device,
+        id: egui::TextureId,
+        view: &TextureView,
+        options: TextureOptions,
+    ) {
+        let comps = if options.alpha_only { 1u32 } else { 4 };
+        let bind_group = self.create_texture_bind_group(device, view, options.sampler, comps);
+        self.textures.insert(
+            id,
+            TextureBind {
+                bind: Arc::new(bind_group),
+                texture: None,
+                comps,
+            },
+        );
+    }
+
+    pub fn free_native_texture(&mut self, id: egui::TextureId) {
+        log::debug!("free_native_texture: {:?}", id);
+        self.textures.remove(&id);
+    }
+
+    /// A 1x1 magenta texture, created on first use.
+    fn placeholder(&mut self, device: &Device, queue: &wgpu::Queue) -> Arc<BindGroup> {
+        if let Some(placeholder) = &self.placeholder {
+            return placeholder.clone();
         }
+        let texture = device.create_texture_with_data(
+            queue,
+            &wgpu::TextureDescriptor {
+                label: Some("egui_placeholder_texture"),
+                size: wgpu::Extent3d {
+                    width: 1,
+                    height: 1,
+                    depth_or_array_layers: 1,
+                },
+                mip_level_count: 1,
+                sample_count: 1,
+                dimension: wgpu::TextureDimension::D2,
+                format: wgpu::TextureFormat::Rgba8UnormSrgb,
+                usage: wgpu::TextureUsages::TEXTURE_BINDING,
+            },
+            &[255, 0, 255, 255],
+        );
+        let view = texture.create_view(&Default::default());
+        let bind_group = self.create_texture_bind_group(device, &view, Default::default(), 4);
+        self.placeholder.insert(Arc::new(bind_group)).clone()
     }
 
+    pub(crate) fn create_texture_bind_group(
+        &mut self,
+        device: &Device,
+        view: &TextureView,
+        options: SamplerOptions,
+        comps: u32,
+    ) -> BindGroup {
+        let sampler = self
+            .samplers
+            .entry(options)
+            .or_insert_with(|| create_sampler(device, options));
+        create_texture_bind_group(
+            device,
+            &self.pipelines.texture_bind_group_layout,
+            view,
+            sampler,
+            comps,
+        )
+    }
+}
+
+/// Per-window drawing state: the egui managed textures of one
+/// [`egui::Context`], the vertex, index and uniform buffers of its meshes.
+pub struct Painter {
+    pub missing_texture_policy: MissingTexturePolicy,
+    /// Options for egui managed textures set by [`Painter::set_texture_options`].
+    texture_options: AHashMap<egui::TextureId, TextureOptions>,
+    /// egui managed textures, keyed by [`egui::TextureId::Managed`].
+    textures: AHashMap<egui::TextureId, TextureBind>,
+    /// Small egui managed images, if enabled with [`Painter::set_texture_atlas`].
+    atlas: Option<Atlas>,
+    /// Packed images whose options changed, moved by the next [`Painter::prepare`].
+    pending_atlas_moves: Vec<egui::TextureId>,
+    /// Screen size of this window, created by the first [`Painter::prepare`].
+    uniform: Option<(wgpu::Buffer, BindGroup)>,
+    /// Pipeline for the target format passed to the last [`Painter::prepare`].
+    pipeline: Option<Arc<wgpu::RenderPipeline>>,
+    /// Vertices of all meshes of the frame, back to back.
+    vertex_arena: BufferArena,
+    /// Indices of all batches of the frame, each relative to its batch's
+    /// first vertex: the 16-bit ones followed by the 32-bit ones.
+    index_arena: BufferArena,
+    /// Staging for the contents of the arenas, kept to reuse its allocation.
+    vertices: Vec<egui::epaint::Vertex>,
+    indices: IndexData,
+    /// Writes the arenas and the uniform buffer.
+    uploader: Uploader,
+    draws: Vec<Draw>,
+    /// Textures freed by the last [`Painter::prepare`], dropped on the next one.
+    pending_free: Vec<egui::TextureId>,
+    /// Counted up by the current [`Painter::prepare`].
+    stats: PaintStats,
+    /// Times the render pass of [`Painter::paint_and_update_textures_into`],
+    /// `None` if the device doesn't support timestamp queries.
+    timer: Option<GpuTimer>,
+}
+
+impl Default for Painter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Painter {
+    pub fn new() -> Self {
+        Self {
+            missing_texture_policy: Default::default(),
+            texture_options: Default::default(),
+            textures: Default::default(),
+            atlas: None,
+            pending_atlas_moves: Vec::new(),
+            uniform: None,
+            pipeline: None,
+            vertex_arena: BufferArena::new("egui_vertex_buffer", BufferUsages::VERTEX),
+            index_arena: BufferArena::new("egui_index_buffer", BufferUsages::INDEX),
+            vertices: Vec::new(),
+            indices: IndexData::default(),
+            uploader: Uploader::default(),
+            draws: Vec::new(),
+            pending_free: Vec::new(),
+            stats: Default::default(),
+            timer: None,
+        }
+    }
+
+    #[allow(clippy::too_many_arguments)]
     pub fn paint_and_update_textures(
         &mut self,
+        renderer: &mut Renderer,
         device: &wgpu::Device,
         queue: &wgpu::Queue,
-        pipeline: &Pipeline,
         target: RenderTarget,
         pixels_per_point: f32,
         clipped_meshes: Vec<egui::ClippedMesh>,
         textures_delta: &egui::TexturesDelta,
-    ) {
-        for (id, image_delta) in &textures_delta.set {
-            self.set_texture(device, queue, pipeline, *id, image_delta);
-            println!("set_texture:{:?}", id);
+    ) -> Result<PaintStats, Error> {
+        let mut encoder = device.create_command_encoder(&wgpu::CommandEncoderDescriptor {
+            label: Some("egui-encoder"),
+        });
+        let stats = self.paint_and_update_textures_into(
+            renderer,
+            device,
+            queue,
+            &mut encoder,
+            target,
+            pixels_per_point,
+            clipped_meshes,
+            textures_delta,
+        )?;
+        queue.submit(Some(encoder.finish()));
+        Ok(stats)
+    }
+
+    /// Like [`Self::paint_and_update_textures`], but records the render pass into
+    /// `encoder` and leaves submission to the caller.
+    ///
+    /// Nothing is recorded into `encoder` if [`Self::prepare`] fails. With
+    /// timestamp queries, `encoder` must be submitted before the next call.
+    #[allow(clippy::too_many_arguments)]
+    pub fn paint_and_update_textures_into(
+        &mut self,
+        renderer: &mut Renderer,
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        encoder: &mut wgpu::CommandEncoder,
+        target: RenderTarget,
+        pixels_per_point: f32,
+        clipped_meshes: Vec<egui::ClippedMesh>,
+        textures_delta: &egui::TexturesDelta,
+    ) -> Result<PaintStats, Error> {
+        let stats = self.prepare(
+            renderer,
+            device,
+            queue,
+            &target.format,
+            target.width,
+            target.height,
+            pixels_per_point,
+            &clipped_meshes,
+            textures_delta,
+        )?;
+
+        let load = if let Some(color) = target.clear_color.as_ref() {
+            wgpu::LoadOp::Clear(*color)
+        } else {
+            wgpu::LoadOp::Load
+        };
+        if self.timer.is_none() {
+            self.timer = GpuTimer::new(device, queue);
+        }
+        let timer_slot = self
+            .timer
+            .as_mut()
+            .and_then(|timer| timer.begin(device, encoder));
+        {
+            let mut rpass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+                label: Some("egui-rpass"),
+                color_attachments: &[wgpu::RenderPassColorAttachment {
+                    view: target.view,
+                    resolve_target: target.resolve_target,
+                    ops: wgpu::Operations { load, store: true },
+                }],
+                depth_stencil_attachment: target.depth_view.map(|view| {
+                    wgpu::RenderPassDepthStencilAttachment {
+                        view,
+                        depth_ops: None,
+                        stencil_ops: None,
+                    }
+                }),
+            });
+            self.render(&mut rpass);
+        } //end rpass
+        if let (Some(timer), Some(slot)) = (&mut self.timer, timer_slot) {
+            timer.end(encoder, slot);
+        }
+
+        self.free_pending_textures();
+        Ok(stats)
+    }
+
+    /// Uploads textures, vertex and index buffers and the screen size uniform
+    /// for `clipped_meshes`, to be drawn by a following [`Self::render`] into a
+    /// `width` x `height` target of `target_format`.
+    ///
+    /// Textures in `textures_delta.free` are kept alive until the next call to
+    /// `prepare`, so they can still be drawn by the following `render`.
+    ///
+    /// Stops at the first error, after which `render` draws nothing.
+    #[allow(clippy::too_many_arguments)]
+    pub fn prepare(
+        &mut self,
+        renderer: &mut Renderer,
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        target_format: &TargetFormat,
+        width: u32,
+        height: u32,
+        pixels_per_point: f32,
+        clipped_meshes: &[egui::ClippedMesh],
+        textures_delta: &egui::TexturesDelta,
+    ) -> Result<PaintStats, Error> {
+        self.free_pending_textures();
+        self.pending_free.extend_from_slice(&textures_delta.free);
+        self.draws.clear();
+        self.stats = PaintStats {
+            clipped_meshes: clipped_meshes.len(),
+            ..Default::default()
+        };
+        self.pipeline = Some(renderer.pipelines.get(device, target_format)?);
+
+        {
+            span!("egui_wgpu::upload_textures");
+            for (id, image_delta) in &textures_delta.set {
+                log::debug!("set_texture: {:?}", id);
+                self.set_texture(renderer, device, queue, *id, image_delta)?;
+            }
+            self.move_atlas_textures(renderer, device, queue);
         }
 
-        self.paint_meshes(
+        self.update_buffers(
+            renderer,
             device,
             queue,
-            target,
-            pipeline,
+            target_format,
+            width,
+            height,
             pixels_per_point,
             clipped_meshes,
-        );
+        )?;
+
+        self.stats.resident_texture_bytes = self
+            .textures
+            .values()
+            .filter_map(|t| t.texture.as_ref())
+            .map(|tex| texture_bytes(tex.format, tex.size, tex.mip_level_count))
+            .sum::<u64>()
+            + self.atlas.as_ref().map_or(0, Atlas::resident_bytes);
+        Ok(self.stats)
+    }
+
+    /// Records the draws uploaded by the last [`Self::prepare`] into `rpass`,
+    /// which must draw to a target of the format passed to `prepare`.
+    pub fn render<'rp>(&'rp self, rpass: &mut wgpu::RenderPass<'rp>) {
+        span!("egui_wgpu::render");
+        let (uniform_bind_group, pipeline, vertex_buffer, index_buffer) = match (
+            &self.uniform,
+            &self.pipeline,
+            self.vertex_arena.buffer(),
+            self.index_arena.buffer(),
+        ) {
+            (Some((_, bind_group)), Some(pipeline), Some(vertices), Some(indices)) => {
+                (bind_group, pipeline, vertices, indices)
+            }
+            _ => return,
+        };
+        rpass.set_pipeline(pipeline);
+        rpass.set_bind_group(0, uniform_bind_group, &[]);
+        rpass.set_vertex_buffer(0, vertex_buffer.slice(..));
+
+        let mut index_format = None;
+        let mut scissor = None;
+        let mut bind: Option<&Arc<BindGroup>> = None;
+        for draw in &self.draws {
+            // wgpu can't bind an empty index buffer slice.
+            if draw.indices.is_empty() {
+                continue;
+            }
+            if index_format != Some(draw.index_format) {
+                let region = match draw.index_format {
+                    wgpu::IndexFormat::Uint16 => index_buffer.slice(..self.indices.u32_offset()),
+                    wgpu::IndexFormat::Uint32 => index_buffer.slice(self.indices.u32_offset()..),
+                };
+                rpass.set_index_buffer(region, draw.index_format);
+                index_format = Some(draw.index_format);
+            }
+            if scissor != Some(draw.scissor) {
+                let [x, y, width, height] = draw.scissor;
+                rpass.set_scissor_rect(x, y, width, height);
+                scissor = Some(draw.scissor);
+            }
+            if !bind.is_some_and(|bind| Arc::ptr_eq(bind, &draw.bind)) {
+                rpass.set_bind_group(1, &draw.bind, &[]);
+                bind = Some(&draw.bind);
+            }
+            rpass.draw_indexed(draw.indices.clone(), draw.base_vertex as i32, 0..1);
+        }
+    }
+
+    /// Packs small egui managed images uploaded from now on into shared
+    /// textures.
+    pub fn set_texture_atlas(&mut self, device: &Device, options: AtlasOptions) {
+        self.atlas = Some(Atlas::new(device, options));
+    }
+
+    /// Sets how vertex, index and uniform data is uploaded from the next
+    /// [`Self::prepare`] on.
+    pub fn set_buffer_upload(&mut self, upload: BufferUpload) {
+        if self.uploader.method() != upload {
+            self.uploader = Uploader::new(upload);
+        }
+    }
+
+    /// GPU time of the render pass of a recent frame in milliseconds, see
+    /// [`crate::EguiWgpu::gpu_time_ms`].
+    pub fn gpu_time_ms(&self) -> Option<f32> {
+        self.timer.as_ref().and_then(GpuTimer::last_ms)
+    }
 
-        for &id in &textures_delta.free {
+    fn free_pending_textures(&mut self) {
+        for id in std::mem::take(&mut self.pending_free) {
             self.free_texture(id);
         }
     }
 
     pub fn set_texture(
         &mut self,
+        renderer: &mut Renderer,
         device: &Device,
         queue: &wgpu::Queue,
-        pipeline: &Pipeline,
         tex_id: egui::TextureId,
         delta: &egui::epaint::ImageDelta,
-    ) {
+    ) -> Result<(), Error> {
+        let pixel_count = match &delta.image {
+            egui::ImageData::Color(image) => image.pixels.len(),
+            egui::ImageData::Alpha(image) => image.pixels.len(),
+        };
+        let image_size = delta.image.size();
+        let size_mismatch = |reason: String| Error::TextureSizeMismatch {
+            id: tex_id,
+            size: image_size,
+            reason,
+        };
+        if image_size[0] * image_size[1] != pixel_count {
+            return Err(size_mismatch(format!("has {} pixels", pixel_count)));
+        }
+        let max_side = device.limits().max_texture_dimension_2d;
+        if image_size[0].max(image_size[1]) > max_side as usize {
+            return Err(Error::TextureTooLarge {
+                id: tex_id,
+                size: image_size,
+                max_side,
+            });
+        }
+
         let (data, fmt, size, comps) = match &delta.image {
             egui::ImageData::Color(image) => {
-                assert_eq!(
-                    image.width() * image.height(),
-                    image.pixels.len(),
-                    "Mismatch between texture size and texel count"
-                );
                 (
                     bytemuck::cast_slice(image.pixels.as_slice()),
                     wgpu::TextureFormat::Rgba8UnormSrgb,
-                    (image.width(), image.height()),
+                    [image.width() as u32, image.height() as u32],
                     4,
                 )
             }
@@ -94,270 +603,640 @@ impl Painter {
                 (
                     image.pixels.as_slice(),
                     wgpu::TextureFormat::R8Unorm,
-                    (image.width(), image.height()),
+                    [image.width() as u32, image.height() as u32],
                     1u32,
                 )
             }
         };
         if let Some(pos) = delta.pos {
-            if let Some(tex) = self.textures.get(&tex_id) {
-                queue.write_texture(
-                    wgpu::ImageCopyTexture {
-                        texture: &tex.texture,
-                        mip_level: 0,
-                        origin: wgpu::Origin3d {
-                            x: pos[0] as u32,
-                            y: pos[1] as u32,
-                            z: 0,
-                        },
-                        aspect: wgpu::TextureAspect::All,
-                    },
-                    data,
-                    wgpu::ImageDataLayout {
-                        offset: 0,
-                        bytes_per_row: NonZeroU32::new(size.0 as u32),
-                        rows_per_image: NonZeroU32::new(size.1 as u32),
-                    },
-                    wgpu::Extent3d {
-                        width: size.0 as u32,
-                        height: size.1 as u32,
-                        depth_or_array_layers: 1,
-                    },
-                )
+            let origin = [pos[0] as u32, pos[1] as u32];
+            let fits = |tex_format: wgpu::TextureFormat, tex_size: [u32; 2]| {
+                if tex_format != fmt {
+                    return Err(size_mismatch(format!(
+                        "is {:?} but the texture is {:?}",
+                        fmt, tex_format
+                    )));
+                }
+                if origin[0] + size[0] > tex_size[0] || origin[1] + size[1] > tex_size[1] {
+                    return Err(size_mismatch(format!(
+                        "at {:?} doesn't fit into the texture of size {:?}",
+                        pos, tex_size
+                    )));
+                }
+                Ok(())
+            };
+            if let Some(atlas) = &self.atlas {
+                if let Some(image_size) = atlas.image_size(tex_id) {
+                    fits(atlas::FORMAT, image_size)?;
+                    atlas.update(queue, tex_id, origin, size, data);
+                    self.stats.texture_bytes_uploaded += data.len() as u64;
+                    return Ok(());
+                }
             }
-            return;
+            let tex = self
+                .textures
+                .get(&tex_id)
+                .and_then(|t| t.texture.as_ref())
+                .ok_or(Error::MissingTexture(tex_id))?;
+            fits(tex.format, tex.size)?;
+            write_texture(queue, &tex.texture, origin, size, data, comps);
+            self.stats.texture_bytes_uploaded += data.len() as u64;
+            if tex.mip_level_count > 1 {
+                let region = [origin[0], origin[1], size[0], size[1]];
+                self.generate_mipmaps(renderer, device, queue, tex_id, region);
+            }
+            return Ok(());
         }
 
-        let tex = device.create_texture_with_data(
-            queue,
-            &wgpu::TextureDescriptor {
-                label: None,
-                size: wgpu::Extent3d {
-                    width: size.0 as u32,
-                    height: size.1 as u32,
-                    depth_or_array_layers: 1,
-                },
-                mip_level_count: 1,
-                sample_count: 1,
-                dimension: wgpu::TextureDimension::D2,
-                format: fmt,
-                usage: wgpu::TextureUsages::COPY_DST | wgpu::TextureUsages::TEXTURE_BINDING,
+        let options = self.texture_options.get(&tex_id).copied().unwrap_or_default();
+        if let Some(atlas) = &mut self.atlas {
+            if comps == 4 && atlas.accepts(size, &options) {
+                self.textures.remove(&tex_id);
+                atlas.upload(renderer, device, queue, tex_id, size, data, options.sampler);
+                self.stats.texture_bytes_uploaded += data.len() as u64;
+                return Ok(());
+            }
+            atlas.free(tex_id);
+        }
+        let (mip_level_count, usage) = if options.mipmaps {
+            (
+                mipmap::mip_level_count(size[0], size[1]),
+                wgpu::TextureUsages::RENDER_ATTACHMENT,
+            )
+        } else {
+            (1, wgpu::TextureUsages::empty())
+        };
+        let tex = device.create_texture(&wgpu::TextureDescriptor {
+            label: None,
+            size: wgpu::Extent3d {
+                width: size[0],
+                height: size[1],
+                depth_or_array_layers: 1,
             },
-            data,
-        );
+            mip_level_count,
+            sample_count: 1,
+            dimension: wgpu::TextureDimension::D2,
+            format: fmt,
+            usage: wgpu::TextureUsages::COPY_DST | wgpu::TextureUsages::TEXTURE_BINDING | usage,
+        });
+        write_texture(queue, &tex, [0, 0], size, data, comps);
+        self.stats.texture_bytes_uploaded += data.len() as u64;
         let view = tex.create_view(&wgpu::TextureViewDescriptor {
             label: None,
             ..Default::default()
         });
-        let comps_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
-            label: None,
-            contents: bytemuck::bytes_of(&comps),
-            usage: BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
-        });
-        let bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
-            label: None,
-            layout: &pipeline.texture_bind_group_layout,
-            entries: &[
-                wgpu::BindGroupEntry {
-                    binding: 0,
-                    resource: wgpu::BindingResource::TextureView(&view),
-                },
-                wgpu::BindGroupEntry {
-                    binding: 1,
-                    resource: wgpu::BindingResource::Sampler(&self.sampler),
-                },
-                wgpu::BindGroupEntry {
-                    binding: 2,
-                    resource: wgpu::BindingResource::Buffer(BufferBinding {
-                        buffer: &comps_buffer,
-                        offset: 0,
-                        size: NonZeroU64::new(4),
-                    }),
-                },
-            ],
-        });
+        let bind_group = renderer.create_texture_bind_group(device, &view, options.sampler, comps);
         self.textures.insert(
             tex_id,
             TextureBind {
-                bind: bind_group,
-                texture: tex,
+                bind: Arc::new(bind_group),
+                texture: Some(ManagedTexture {
+                    texture: tex,
+                    view,
+                    format: fmt,
+                    size,
+                    mip_level_count,
+                }),
+                comps,
             },
         );
+        if mip_level_count > 1 {
+            self.generate_mipmaps(renderer, device, queue, tex_id, [0, 0, size[0], size[1]]);
+        }
+        Ok(())
+    }
+
+    /// Redraws the part of the mip chain of `tex_id` covering `region` of level 0.
+    ///
+    /// Submits its own command buffer, so that the downsampling runs after the
+    /// upload that preceded it.
+    fn generate_mipmaps(
+        &self,
+        renderer: &mut Renderer,
+        device: &Device,
+        queue: &wgpu::Queue,
+        tex_id: egui::TextureId,
+        region: [u32; 4],
+    ) {
+        let tex = match self.textures.get(&tex_id).and_then(|t| t.texture.as_ref()) {
+            Some(tex) => tex,
+            None => return,
+        };
+        let mut encoder = device.create_command_encoder(&wgpu::CommandEncoderDescriptor {
+            label: Some("egui-mipmap-encoder"),
+        });
+        renderer.mipmap_generator.generate(
+            device,
+            &mut encoder,
+            &tex.texture,
+            tex.format,
+            tex.size,
+            tex.mip_level_count,
+            region,
+        );
+        queue.submit(Some(encoder.finish()));
+    }
+
+    /// Sets how the egui managed texture `id` is stored and sampled, e.g. the id
+    /// of a [`egui::TextureHandle`] returned by [`egui::Context::load_texture`].
+    ///
+    /// Sampler options apply to the already uploaded texture, while
+    /// [`TextureOptions::mipmaps`] takes effect the next time egui uploads the
+    /// whole image, so set it right after loading the texture.
+    /// Native textures take their options in [`Self::update_native_texture`].
+    ///
+    /// Images in the atlas are moved to a matching page or a texture of their
+    /// own by the next [`Self::prepare`].
+    pub fn set_texture_options(
+        &mut self,
+        renderer: &mut Renderer,
+        device: &Device,
+        id: egui::TextureId,
+        options: TextureOptions,
+    ) {
+        self.texture_options.insert(id, options);
+        if self.atlas.as_ref().and_then(|atlas| atlas.image_size(id)).is_some() {
+            self.pending_atlas_moves.push(id);
+        }
+        if let Some(tex_bind) = self.textures.get_mut(&id) {
+            if let Some(tex) = &tex_bind.texture {
+                tex_bind.bind = Arc::new(renderer.create_texture_bind_group(
+                    device,
+                    &tex.view,
+                    options.sampler,
+                    tex_bind.comps,
+                ));
+            }
+        }
     }
 
     pub fn free_texture(&mut self, id: egui::TextureId) {
-        println!("free_texture:{:?}", id);
+        log::debug!("free_texture: {:?}", id);
         self.textures.remove(&id);
+        self.texture_options.remove(&id);
+        if let Some(atlas) = &mut self.atlas {
+            atlas.free(id);
+        }
     }
 
-    fn paint_meshes(
+    /// Applies options changed by [`Self::set_texture_options`] to images in
+    /// the atlas: moves them to a page with their sampler, or into a texture
+    /// of their own if the atlas can't hold them anymore.
+    fn move_atlas_textures(
         &mut self,
+        renderer: &mut Renderer,
         device: &Device,
         queue: &wgpu::Queue,
-        target: RenderTarget,
-        pipeline: &Pipeline,
-        pixels_per_point: f32,
-
-        clipped_meshes: Vec<egui::ClippedMesh>,
     ) {
-        let load = if let Some(color) = target.clear_color.as_ref() {
-            wgpu::LoadOp::Clear(*color)
-        } else {
-            wgpu::LoadOp::Load
+        let atlas = match &mut self.atlas {
+            Some(atlas) => atlas,
+            None => return,
         };
-        let physical_width = target.width as f32;
-        let physical_height = target.height as f32;
+        for id in std::mem::take(&mut self.pending_atlas_moves) {
+            let size = match atlas.image_size(id) {
+                Some(size) => size,
+                None => continue,
+            };
+            let options = self.texture_options.get(&id).copied().unwrap_or_default();
+            if atlas.accepts(size, &options) {
+                atlas.set_sampler(renderer, device, queue, id, options.sampler);
+                continue;
+            }
+            // Mipmaps are allocated with the next upload of the whole image.
+            let (texture, size) = match atlas.take(device, queue, id) {
+                Some(taken) => taken,
+                None => continue,
+            };
+            let view = texture.create_view(&Default::default());
+            let bind_group = renderer.create_texture_bind_group(device, &view, options.sampler, 4);
+            self.textures.insert(
+                id,
+                TextureBind {
+                    bind: Arc::new(bind_group),
+                    texture: Some(ManagedTexture {
+                        texture,
+                        view,
+                        format: wgpu::TextureFormat::Rgba8UnormSrgb,
+                        size,
+                        mip_level_count: 1,
+                    }),
+                    comps: 4,
+                },
+            );
+        }
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn update_buffers(
+        &mut self,
+        renderer: &mut Renderer,
+        device: &Device,
+        queue: &wgpu::Queue,
+        target_format: &TargetFormat,
+        width: u32,
+        height: u32,
+        pixels_per_point: f32,
+        clipped_meshes: &[egui::ClippedMesh],
+    ) -> Result<(), Error> {
+        span!("egui_wgpu::upload_buffers");
+        let physical_width = width as f32;
+        let physical_height = height as f32;
         let width_point = physical_width/pixels_per_point;
         let height_point = physical_height/pixels_per_point;
 
-        let buffer = &pipeline.uniform_buffer.buffer;
-        queue.write_buffer(
-            buffer,
-            0,
-            bytemuck::bytes_of(&UniformBufferData {
-                screen_size: [width_point, height_point as f32],
-            }),
+        let stats = &mut self.stats;
+        let (buffer, _) = self.uniform.get_or_insert_with(|| {
+            stats.buffers_allocated += 1;
+            create_uniform(device, &renderer.pipelines.uniform_bind_group_layout)
+        });
+        let uniform = UniformBufferData {
+            screen_size: [width_point, height_point],
+            output_scale: target_format.output_scale().unwrap_or(1.0),
+            _padding: 0.0,
+        };
+        self.uploader
+            .write(device, queue, buffer, 0, bytemuck::bytes_of(&uniform));
+        stats.buffer_bytes_written += std::mem::size_of_val(&uniform) as u64;
+
+        self.vertices.clear();
+        self.indices.clear();
+        for ClippedMesh(clip_rect, mesh) in clipped_meshes {
+            let (scissor, bounds) =
+                match cull_mesh(mesh, *clip_rect, pixels_per_point, width, height) {
+                    Some(visible) => visible,
+                    None => {
+                        self.stats.skipped_meshes += 1;
+                        continue;
+                    }
+                };
+            let atlas_entry = self
+                .atlas
+                .as_ref()
+                .and_then(|atlas| atlas.lookup(mesh.texture_id));
+            let (bind, uv_rect) = match atlas_entry {
+                Some((bind, uv_rect)) => (Some(bind.clone()), Some(uv_rect)),
+                None => {
+                    let bind = match mesh.texture_id {
+                        egui::TextureId::Managed(_) => self.textures.get(&mesh.texture_id),
+                        egui::TextureId::User(_) => renderer.textures.get(&mesh.texture_id),
+                    }
+                    .map(|tex_bind| tex_bind.bind.clone());
+                    (bind, None)
+                }
+            };
+            let bind = match (bind, self.missing_texture_policy) {
+                (Some(bind), _) => bind,
+                (None, MissingTexturePolicy::Skip) => {
+                    log::warn!("no texture with id {:?}, skipping mesh", mesh.texture_id);
+                    self.stats.skipped_meshes += 1;
+                    continue;
+                }
+                (None, MissingTexturePolicy::Placeholder) => {
+                    log::warn!("no texture with id {:?}, drawing placeholder", mesh.texture_id);
+                    renderer.placeholder(device, queue)
+                }
+                (None, MissingTexturePolicy::Error) => {
+                    self.draws.clear();
+                    return Err(Error::MissingTexture(mesh.texture_id));
+                }
+            };
+            self.stats.unbatched_draw_calls += 1;
+            self.stats.vertices += mesh.vertices.len();
+            self.stats.indices += mesh.indices.len();
+
+            let base_vertex = self.vertices.len() as u32;
+            match uv_rect {
+                Some(uv_rect) => {
+                    self.vertices.extend(mesh.vertices.iter().map(|vertex| {
+                        egui::epaint::Vertex {
+                            uv: uv_rect.min + vertex.uv.to_vec2() * uv_rect.size(),
+                            ..*vertex
+                        }
+                    }))
+                }
+                None => self.vertices.extend_from_slice(&mesh.vertices),
+            }
+            match self.draws.last_mut() {
+                Some(batch)
+                    if Arc::ptr_eq(&batch.bind, &bind)
+                        && can_batch(batch.scissor, scissor, bounds)
+                        && (batch.index_format == wgpu::IndexFormat::Uint32
+                            || index_format(self.vertices.len() - batch.base_vertex as usize)
+                                == wgpu::IndexFormat::Uint16) =>
+                {
+                    let offset = base_vertex - batch.base_vertex;
+                    batch.indices.end =
+                        self.indices.extend(batch.index_format, &mesh.indices, offset);
+                }
+                _ => {
+                    let index_format = index_format(mesh.vertices.len());
+                    let first_index = self.indices.len(index_format);
+                    let end = self.indices.extend(index_format, &mesh.indices, 0);
+                    self.draws.push(Draw {
+                        scissor,
+                        bind,
+                        index_format,
+                        indices: first_index..end,
+                        base_vertex,
+                    });
+                }
+            }
+        }
+        self.stats.draw_calls = self.draws.len();
+
+        self.vertex_arena.upload(
+            device,
+            queue,
+            &mut self.uploader,
+            &mut self.stats,
+            &[bytemuck::cast_slice(&self.vertices)],
+        );
+        self.indices.pad();
+        self.index_arena.upload(
+            device,
+            queue,
+            &mut self.uploader,
+            &mut self.stats,
+            &[
+                bytemuck::cast_slice(&self.indices.u16),
+                bytemuck::cast_slice(&self.indices.u32),
+            ],
         );
+        self.uploader.submit(device, queue);
+        Ok(())
+    }
+}
 
-        for (i, ClippedMesh(_, mesh)) in clipped_meshes.iter().enumerate() {
-            update_buffer_at(
-                device,
-                queue,
-                i,
-                &mut self.vertex_buffers,
-                bytemuck::cast_slice(mesh.vertices.as_slice()),
-                wgpu::BufferUsages::VERTEX,
-            );
-            update_buffer_at(
-                device,
-                queue,
-                i,
-                &mut self.index_buffers,
-                bytemuck::cast_slice(mesh.indices.as_slice()),
-                wgpu::BufferUsages::INDEX,
-            );
+/// Indices of the batches of a frame, in a 16-bit and a 32-bit region.
+#[derive(Default)]
+struct IndexData {
+    u16: Vec<u16>,
+    u32: Vec<u32>,
+}
+
+impl IndexData {
+    fn clear(&mut self) {
+        self.u16.clear();
+        self.u32.clear();
+    }
+
+    fn len(&self, format: wgpu::IndexFormat) -> u32 {
+        match format {
+            wgpu::IndexFormat::Uint16 => self.u16.len() as u32,
+            wgpu::IndexFormat::Uint32 => self.u32.len() as u32,
         }
+    }
 
-        let mut encoder = device.create_command_encoder(&wgpu::CommandEncoderDescriptor {
-            label: Some("egui-encoder"),
-        });
-        {
-            let mut rpass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
-                label: Some("egui-rpass"),
-                color_attachments: &[wgpu::RenderPassColorAttachment {
-                    view: target.view,
-                    resolve_target: None,
-                    ops: wgpu::Operations { load, store: true },
-                }],
-                depth_stencil_attachment: None,
-            });
+    /// Appends `indices` plus `offset` to the region of `format` and returns
+    /// the new length of that region.
+    fn extend(&mut self, format: wgpu::IndexFormat, indices: &[u32], offset: u32) -> u32 {
+        match format {
+            wgpu::IndexFormat::Uint16 => self
+                .u16
+                .extend(indices.iter().map(|&index| (index + offset) as u16)),
+            wgpu::IndexFormat::Uint32 => self
+                .u32
+                .extend(indices.iter().map(|&index| index + offset)),
+        }
+        self.len(format)
+    }
 
-            rpass.set_pipeline(&pipeline.pipeline);
-            rpass.set_bind_group(0, &pipeline.uniform_bind_group, &[]);
+    /// Pads the 16-bit region to a multiple of [`wgpu::COPY_BUFFER_ALIGNMENT`]
+    /// bytes, so that it can be uploaded and the 32-bit region is aligned.
+    fn pad(&mut self) {
+        if self.u16.len() % 2 == 1 {
+            self.u16.push(0);
+        }
+    }
 
-            for (i, ClippedMesh(clip_rect, mesh)) in clipped_meshes.into_iter().enumerate() {
-                // Transform clip rect to physical pixels.
-                let clip_min_x = pixels_per_point * clip_rect.min.x;
-                let clip_min_y = pixels_per_point * clip_rect.min.y;
-                let clip_max_x = pixels_per_point * clip_rect.max.x;
-                let clip_max_y = pixels_per_point * clip_rect.max.y;
+    /// Byte offset of the 32-bit region in the index arena, after [`Self::pad`].
+    fn u32_offset(&self) -> u64 {
+        (self.u16.len() * std::mem::size_of::<u16>()) as u64
+    }
+}
 
-                // Make sure clip rect can fit within an `u32`.
-                let clip_min_x = clip_min_x.clamp(0.0, physical_width as f32);
-                let clip_min_y = clip_min_y.clamp(0.0, physical_height as f32);
-                let clip_max_x = clip_max_x.clamp(clip_min_x, physical_width as f32);
-                let clip_max_y = clip_max_y.clamp(clip_min_y, physical_height as f32);
+/// Smallest index format that can address `vertex_count` vertices.
+fn index_format(vertex_count: usize) -> wgpu::IndexFormat {
+    if vertex_count <= 1 << 16 {
+        wgpu::IndexFormat::Uint16
+    } else {
+        wgpu::IndexFormat::Uint32
+    }
+}
 
-                let clip_min_x = clip_min_x.round() as u32;
-                let clip_min_y = clip_min_y.round() as u32;
-                let clip_max_x = clip_max_x.round() as u32;
-                let clip_max_y = clip_max_y.round() as u32;
+/// Transforms `clip_rect` in points into a scissor rect `[x, y, width, height]`
+/// in physical pixels of a `width` x `height` target, `None` if nothing is visible.
+pub(crate) fn scissor_rect(
+    clip_rect: egui::Rect,
+    pixels_per_point: f32,
+    width: u32,
+    height: u32,
+) -> Option<[u32; 4]> {
+    let physical_width = width as f32;
+    let physical_height = height as f32;
 
-                let width = (clip_max_x - clip_min_x).max(1);
-                let height = (clip_max_y - clip_min_y).max(1);
+    // Transform clip rect to physical pixels.
+    let clip_min_x = pixels_per_point * clip_rect.min.x;
+    let clip_min_y = pixels_per_point * clip_rect.min.y;
+    let clip_max_x = pixels_per_point * clip_rect.max.x;
+    let clip_max_y = pixels_per_point * clip_rect.max.y;
 
-                {
-                    // Clip scissor rectangle to target size.
-                    let x = clip_min_x.min(physical_width as u32);
-                    let y = clip_min_y.min(physical_height as u32);
-                    let width = width.min(physical_width as u32 - x);
-                    let height = height.min(physical_height as u32 - y);
-
-                    // Skip rendering with zero-sized clip areas.
-                    if width == 0 || height == 0 {
-                        continue;
-                    }
-                    rpass.set_scissor_rect(x, y, width, height);
-                }
-                if let Some(tex_bind) = self.textures.get(&mesh.texture_id) {
-                    rpass.set_bind_group(1, &tex_bind.bind, &[]);
-                } else {
-                    eprintln!("no texture with id:{:?}", mesh.texture_id);
-                    continue;
-                }
+    // Make sure clip rect can fit within an `u32`.
+    let clip_min_x = clip_min_x.clamp(0.0, physical_width);
+    let clip_min_y = clip_min_y.clamp(0.0, physical_height);
+    let clip_max_x = clip_max_x.clamp(clip_min_x, physical_width);
+    let clip_max_y = clip_max_y.clamp(clip_min_y, physical_height);
 
-                let buffer = &self.vertex_buffers[i].buffer;
+    // Nothing is visible of clip rects outside the target or without area.
+    if clip_max_x <= clip_min_x || clip_max_y <= clip_min_y {
+        return None;
+    }
 
-                rpass.set_vertex_buffer(0, buffer.slice(..));
+    let clip_min_x = clip_min_x.round() as u32;
+    let clip_min_y = clip_min_y.round() as u32;
+    let clip_max_x = clip_max_x.round() as u32;
+    let clip_max_y = clip_max_y.round() as u32;
 
-                let buffer = &self.index_buffers[i].buffer;
-                rpass.set_index_buffer(buffer.slice(..), wgpu::IndexFormat::Uint32);
+    let clip_width = (clip_max_x - clip_min_x).max(1);
+    let clip_height = (clip_max_y - clip_min_y).max(1);
 
-                rpass.draw_indexed(0..mesh.indices.len() as u32, 0, 0..1);
-            }
-        } //end rpass
-        let cmd = encoder.finish();
-        queue.submit(Some(cmd));
+    // Clip scissor rectangle to target size.
+    let x = clip_min_x.min(width);
+    let y = clip_min_y.min(height);
+    let clip_width = clip_width.min(width - x);
+    let clip_height = clip_height.min(height - y);
+
+    // Skip rendering with zero-sized clip areas.
+    if clip_width == 0 || clip_height == 0 {
+        None
+    } else {
+        Some([x, y, clip_width, clip_height])
     }
 }
 
-#[inline(always)]
-fn update_buffer(
-    device: &Device,
+/// The scissor rect of `mesh` and its bounding box in physical pixels, `None`
+/// if nothing of it is visible in a `width` x `height` target: it has no
+/// triangles, its `clip_rect` is empty or outside the target, or its vertices
+/// lie outside `clip_rect`.
+fn cull_mesh(
+    mesh: &egui::Mesh,
+    clip_rect: egui::Rect,
+    pixels_per_point: f32,
+    width: u32,
+    height: u32,
+) -> Option<([u32; 4], egui::Rect)> {
+    if mesh.indices.is_empty() {
+        return None;
+    }
+    let scissor = scissor_rect(clip_rect, pixels_per_point, width, height)?;
+    let bounds = mesh.calc_bounds();
+    let bounds = egui::Rect::from_min_max(
+        (bounds.min.to_vec2() * pixels_per_point).to_pos2(),
+        (bounds.max.to_vec2() * pixels_per_point).to_pos2(),
+    );
+    let [x, y, scissor_width, scissor_height] = scissor;
+    let scissor_rect = egui::Rect::from_min_size(
+        egui::pos2(x as f32, y as f32),
+        egui::vec2(scissor_width as f32, scissor_height as f32),
+    );
+    scissor_rect.intersects(bounds).then_some((scissor, bounds))
+}
+
+/// Whether a mesh clipped to `scissor` can be drawn with the `batch_scissor` of
+/// the batch before it. Either the scissors are equal, or the mesh's `bounds`
+/// in physical pixels lie inside both, so neither clips it.
+fn can_batch(batch_scissor: [u32; 4], scissor: [u32; 4], bounds: egui::Rect) -> bool {
+    let contains_bounds = |[x, y, width, height]: [u32; 4]| {
+        let rect = egui::Rect::from_min_size(
+            egui::pos2(x as f32, y as f32),
+            egui::vec2(width as f32, height as f32),
+        );
+        rect.contains_rect(bounds)
+    };
+    batch_scissor == scissor || (contains_bounds(batch_scissor) && contains_bounds(scissor))
+}
+
+/// Writes `data`, `size` texels of `bytes_per_texel` bytes each, into mip
+/// level 0 of `texture` at `origin`.
+pub(crate) fn write_texture(
     queue: &wgpu::Queue,
-    buffer: &mut SizedBuffer,
+    texture: &Texture,
+    origin: [u32; 2],
+    size: [u32; 2],
     data: &[u8],
-    usage: wgpu::BufferUsages,
+    bytes_per_texel: u32,
 ) {
-    // println!("size change={}", buffer.size != data.len());
-    if buffer.size < data.len() {
-        *buffer = create_buffer(device, data, usage);
-    } else {
-        queue.write_buffer(&buffer.buffer, 0, data);
-    }
+    queue.write_texture(
+        wgpu::ImageCopyTexture {
+            texture,
+            mip_level: 0,
+            origin: wgpu::Origin3d {
+                x: origin[0],
+                y: origin[1],
+                z: 0,
+            },
+            aspect: wgpu::TextureAspect::All,
+        },
+        data,
+        wgpu::ImageDataLayout {
+            offset: 0,
+            bytes_per_row: NonZeroU32::new(size[0] * bytes_per_texel),
+            rows_per_image: NonZeroU32::new(size[1]),
+        },
+        wgpu::Extent3d {
+            width: size[0],
+            height: size[1],
+            depth_or_array_layers: 1,
+        },
+    )
 }
 
-#[inline(always)]
-fn create_buffer(device: &Device, data: &[u8], usage: BufferUsages) -> SizedBuffer {
-    let buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
-        label: None,
-        contents: data,
-        usage: usage | wgpu::BufferUsages::COPY_DST,
+/// Uniform buffer holding a [`UniformBufferData`] and its bind group.
+fn create_uniform(device: &Device, layout: &wgpu::BindGroupLayout) -> (wgpu::Buffer, BindGroup) {
+    let buffer = device.create_buffer(&wgpu::BufferDescriptor {
+        label: Some("egui_uniform_buffer"),
+        size: std::mem::size_of::<UniformBufferData>() as u64,
+        usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
+        mapped_at_creation: false,
+    });
+    let bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+        label: Some("egui_uniform_bind_group"),
+        layout,
+        entries: &[wgpu::BindGroupEntry {
+            binding: 0,
+            resource: wgpu::BindingResource::Buffer(wgpu::BufferBinding {
+                buffer: &buffer,
+                offset: 0,
+                size: None,
+            }),
+        }],
     });
+    (buffer, bind_group)
+}
 
-    SizedBuffer {
-        buffer,
-        size: data.len(),
-    }
+fn create_sampler(device: &Device, options: SamplerOptions) -> wgpu::Sampler {
+    device.create_sampler(&wgpu::SamplerDescriptor {
+        label: None,
+        address_mode_u: options.wrap_mode,
+        address_mode_v: options.wrap_mode,
+        address_mode_w: options.wrap_mode,
+        mag_filter: options.magnification,
+        min_filter: options.minification,
+        mipmap_filter: options.mipmap_filter,
+        ..Default::default()
+    })
 }
-fn update_buffer_at(
+
+fn create_texture_bind_group(
     device: &Device,
-    queue: &wgpu::Queue,
-    i: usize,
-    buffers: &mut Vec<SizedBuffer>,
-    data: &[u8],
-    usage: wgpu::BufferUsages,
-) {
-    if buffers.len() > i {
-        let buffer = &mut buffers[i];
-        update_buffer(device, queue, buffer, data, usage);
-    } else {
-        buffers.push(create_buffer(device, data, usage));
-    }
+    layout: &wgpu::BindGroupLayout,
+    view: &TextureView,
+    sampler: &wgpu::Sampler,
+    comps: u32,
+) -> BindGroup {
+    let comps_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+        label: None,
+        contents: bytemuck::bytes_of(&comps),
+        usage: BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
+    });
+    device.create_bind_group(&wgpu::BindGroupDescriptor {
+        label: None,
+        layout,
+        entries: &[
+            wgpu::BindGroupEntry {
+                binding: 0,
+                resource: wgpu::BindingResource::TextureView(view),
+            },
+            wgpu::BindGroupEntry {
+                binding: 1,
+                resource: wgpu::BindingResource::Sampler(sampler),
+            },
+            wgpu::BindGroupEntry {
+                binding: 2,
+                resource: wgpu::BindingResource::Buffer(BufferBinding {
+                    buffer: &comps_buffer,
+                    offset: 0,
+                    size: NonZeroU64::new(4),
+                }),
+            },
+        ],
+    })
+}
+
+/// Memory used by a `format` texture of `size` with `mip_level_count` levels.
+fn texture_bytes(format: wgpu::TextureFormat, size: [u32; 2], mip_level_count: u32) -> u64 {
+    let bytes_per_texel = format.describe().block_size as u64;
+    (0..mip_level_count)
+        .map(|level| {
+            let width = (size[0] >> level).max(1) as u64;
+            let height = (size[1] >> level).max(1) as u64;
+            width * height * bytes_per_texel
+        })
+        .sum()
 }
 
 #[cfg(feature = "epi")]
-impl epi::NativeTexture for Painter {
+impl epi::NativeTexture for Renderer {
     type Texture = TextureBind;
 
     fn register_native_texture(&mut self, native: Self::Texture) -> egui::TextureId {
@@ -371,3 +1250,101 @@ impl epi::NativeTexture for Painter {
         self.textures.insert(id, replacing);
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn clip_rect_edge_cases() {
+        let rect = |min: [f32; 2], max: [f32; 2]| {
+            egui::Rect::from_min_max(egui::pos2(min[0], min[1]), egui::pos2(max[0], max[1]))
+        };
+        let scissor = |clip_rect| scissor_rect(clip_rect, 2.0, 200, 100);
+
+        assert_eq!(scissor(rect([10.0, 5.0], [20.0, 15.0])), Some([20, 10, 20, 20]));
+        assert_eq!(scissor(egui::Rect::EVERYTHING), Some([0, 0, 200, 100]));
+        // Clamped to the target.
+        assert_eq!(scissor(rect([-10.0, 40.0], [20.0, 80.0])), Some([0, 80, 40, 20]));
+        // Touching the target from outside.
+        assert_eq!(scissor(rect([100.0, 0.0], [120.0, 10.0])), None);
+        assert_eq!(scissor(rect([-20.0, 0.0], [0.0, 10.0])), None);
+        assert_eq!(scissor(rect([0.0, -20.0], [10.0, 0.0])), None);
+        assert_eq!(scissor(rect([0.0, 60.0], [10.0, 70.0])), None);
+        // Without area.
+        assert_eq!(scissor(rect([10.0, 10.0], [10.0, 20.0])), None);
+        assert_eq!(scissor(egui::Rect::NOTHING), None);
+        // Thinner than a pixel, but still covering part of one.
+        assert_eq!(scissor(rect([10.0, 10.0], [10.1, 20.0])), Some([20, 20, 1, 20]));
+    }
+
+    #[test]
+    fn culls_meshes_outside_their_clip_rect() {
+        let mut mesh = egui::Mesh::default();
+        let mesh_rect = egui::Rect::from_min_max(egui::pos2(10.0, 10.0), egui::pos2(20.0, 20.0));
+        mesh.add_colored_rect(mesh_rect, egui::Color32::WHITE);
+        let cull = |mesh: &egui::Mesh, clip_rect| {
+            cull_mesh(mesh, clip_rect, 1.0, 100, 100).map(|(scissor, _)| scissor)
+        };
+
+        assert_eq!(cull(&mesh, egui::Rect::EVERYTHING), Some([0, 0, 100, 100]));
+        let overlapping = egui::Rect::from_min_max(egui::pos2(15.0, 0.0), egui::pos2(50.0, 50.0));
+        assert_eq!(cull(&mesh, overlapping), Some([15, 0, 35, 50]));
+        let beside = egui::Rect::from_min_max(egui::pos2(30.0, 0.0), egui::pos2(50.0, 50.0));
+        assert_eq!(cull(&mesh, beside), None);
+        // Scrolled out of the target.
+        mesh.translate(egui::vec2(0.0, -50.0));
+        assert_eq!(cull(&mesh, egui::Rect::EVERYTHING), None);
+        assert_eq!(cull(&egui::Mesh::default(), egui::Rect::EVERYTHING), None);
+        // Vertices without indices would become a draw with an empty index range.
+        mesh.translate(egui::vec2(0.0, 50.0));
+        mesh.indices.clear();
+        assert!(!mesh.is_empty());
+        assert_eq!(cull(&mesh, egui::Rect::EVERYTHING), None);
+    }
+
+    #[test]
+    fn batches_equal_or_unclipping_scissors() {
+        let window = [0, 0, 100, 100];
+        let button = [10, 10, 20, 20];
+        let inside_button = egui::Rect::from_min_max(egui::pos2(12.0, 12.0), egui::pos2(28.0, 28.0));
+        let past_button = egui::Rect::from_min_max(egui::pos2(12.0, 12.0), egui::pos2(40.0, 28.0));
+
+        assert!(can_batch(button, button, past_button));
+        // A mesh inside its clip rect isn't clipped by the window's either.
+        assert!(can_batch(window, button, inside_button));
+        assert!(can_batch(button, window, inside_button));
+        // Drawing this one with the window's scissor would show what the
+        // button's clips away.
+        assert!(!can_batch(window, button, past_button));
+        assert!(!can_batch(button, window, past_button));
+    }
+
+    #[test]
+    fn large_meshes_fall_back_to_32_bit_indices() {
+        assert_eq!(index_format(3), wgpu::IndexFormat::Uint16);
+        assert_eq!(index_format(1 << 16), wgpu::IndexFormat::Uint16);
+        assert_eq!(index_format((1 << 16) + 1), wgpu::IndexFormat::Uint32);
+
+        let mut indices = IndexData::default();
+        assert_eq!(indices.extend(wgpu::IndexFormat::Uint16, &[0, 1, 2], 0), 3);
+        assert_eq!(indices.extend(wgpu::IndexFormat::Uint16, &[0, 1, 65_532], 3), 6);
+        assert_eq!(indices.extend(wgpu::IndexFormat::Uint32, &[0, 70_000, 1], 0), 3);
+        assert_eq!(indices.u16, [0, 1, 2, 3, 4, 65_535]);
+        assert_eq!(indices.u32, [0, 70_000, 1]);
+
+        indices.extend(wgpu::IndexFormat::Uint16, &[7], 0);
+        indices.pad();
+        assert_eq!(indices.u16.len(), 8);
+        assert_eq!(indices.u32_offset() % wgpu::COPY_BUFFER_ALIGNMENT, 0);
+    }
+
+    #[test]
+    fn texture_bytes_include_mip_chain() {
+        let format = wgpu::TextureFormat::Rgba8UnormSrgb;
+        assert_eq!(texture_bytes(format, [4, 2], 1), 32);
+        // 4x2 + 2x1 + 1x1 texels.
+        assert_eq!(texture_bytes(format, [4, 2], 3), 44);
+        assert_eq!(texture_bytes(wgpu::TextureFormat::R8Unorm, [4, 2], 3), 11);
+    }
+}
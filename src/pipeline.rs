@@ -1,132 +1,296 @@
-use std::{
-    borrow::Cow,
-    num::{NonZeroU32, NonZeroU64},
-};
+use std::{borrow::Cow, num::NonZeroU64, sync::Arc};
+
+use ahash::AHashMap;
 
 use bytemuck::{Pod, Zeroable};
-use wgpu::{util::DeviceExt, *};
+use wgpu::*;
+
+use crate::Error;
 
 #[repr(C)]
 #[derive(Debug, Copy, Clone, Pod, Zeroable)]
 pub struct UniformBufferData {
     pub screen_size: [f32; 2],
+    /// Multiplier for linear output colors, see [`TargetFormat::paper_white`].
+    pub output_scale: f32,
+    pub _padding: f32,
 }
 pub struct SizedBuffer {
     pub buffer: Buffer,
     pub size: usize,
 }
 
-pub struct Pipeline {
-    pub pipeline: RenderPipeline,
-
-    pub uniform_bind_group_layout: BindGroupLayout,
-    pub texture_bind_group_layout: BindGroupLayout,
-    pub uniform_bind_group: BindGroup,
-    pub uniform_buffer: SizedBuffer,
+/// Format of a [`crate::RenderTarget`]. The painter creates one render pipeline
+/// for every distinct format it draws to.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct TargetFormat {
+    pub format: TextureFormat,
+    /// Sample count of [`crate::RenderTarget::view`], `1` unless multisampled.
+    pub sample_count: u32,
+    /// Format of [`crate::RenderTarget::depth_view`], if there is one. egui
+    /// neither tests nor writes depth.
+    pub depth_format: Option<TextureFormat>,
+    /// Brightness of egui's white on float (HDR / scRGB) targets such as
+    /// [`TextureFormat::Rgba16Float`], treated as `1.0` on float targets if
+    /// `None`. Ignored by other targets.
+    ///
+    /// Vertex colors and textures are converted from sRGB to linear and
+    /// multiplied by `paper_white`, the output value of egui's white. With scRGB,
+    /// where `1.0` is 80 nits, a `paper_white` of `2.5` shows white at 200 nits.
+    /// Can be changed between frames.
+    pub paper_white: Option<f32>,
 }
-impl Pipeline {
-    pub fn new(device: &Device, output_format: TextureFormat, msaa_samples: u32) -> Self {
-        create_pipeline(device, output_format, msaa_samples)
+
+impl TargetFormat {
+    /// A single-sampled target without depth.
+    pub fn new(format: TextureFormat) -> Self {
+        Self {
+            format,
+            sample_count: 1,
+            depth_format: None,
+            paper_white: None,
+        }
+    }
+
+    /// Creates a multisampled texture matching this format and sample count, to
+    /// be used as [`crate::RenderTarget::view`] with the single-sampled output
+    /// as [`crate::RenderTarget::resolve_target`].
+    ///
+    /// Recreate it whenever the output is resized.
+    pub fn create_msaa_texture(&self, device: &Device, width: u32, height: u32) -> Texture {
+        device.create_texture(&wgpu::TextureDescriptor {
+            label: Some("egui_msaa_texture"),
+            size: wgpu::Extent3d {
+                width,
+                height,
+                depth_or_array_layers: 1,
+            },
+            mip_level_count: 1,
+            sample_count: self.sample_count,
+            dimension: wgpu::TextureDimension::D2,
+            format: self.format,
+            usage: wgpu::TextureUsages::RENDER_ATTACHMENT,
+        })
+    }
+
+    /// Multiplier for linear output colors, `None` if the target isn't a float
+    /// format and gets sRGB or gamma-space colors instead.
+    pub(crate) fn output_scale(&self) -> Option<f32> {
+        is_float_format(self.format).then(|| self.paper_white.unwrap_or(1.0))
+    }
+
+    fn key(&self) -> PipelineKey {
+        PipelineKey {
+            format: self.format,
+            sample_count: self.sample_count,
+            depth_format: self.depth_format,
+            hdr: self.output_scale().is_some(),
+        }
     }
 }
 
-#[inline(always)]
-fn create_pipeline(device: &Device, output_format: TextureFormat, msaa_samples: u32) -> Pipeline {
-    let shader = wgpu::ShaderModuleDescriptor {
-        label: Some("shader/egui.wgsl"),
-        source: wgpu::ShaderSource::Wgsl(Cow::Borrowed(include_str!("shader/egui.wgsl"))),
-    };
-    let module = device.create_shader_module(&shader);
-
-    let uniform_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
-        label: Some("egui_uniform_buffer"),
-        contents: bytemuck::cast_slice(&[UniformBufferData {
-            screen_size: [0.0, 0.0],
-        }]),
-        usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
-    });
-    let uniform_buffer = SizedBuffer {
-        buffer: uniform_buffer,
-        size: std::mem::size_of::<UniformBufferData>(),
-    };
+/// What a render pipeline depends on: everything in [`TargetFormat`] except the
+/// value of `paper_white`, which is a uniform.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+struct PipelineKey {
+    format: TextureFormat,
+    sample_count: u32,
+    depth_format: Option<TextureFormat>,
+    hdr: bool,
+}
 
-    let uniform_bind_group_layout =
-        device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
-            label: Some("egui_uniform_bind_group_layout"),
-            entries: &[wgpu::BindGroupLayoutEntry {
-                binding: 0,
-                visibility: wgpu::ShaderStages::VERTEX,
-                ty: wgpu::BindingType::Buffer {
-                    has_dynamic_offset: false,
-                    min_binding_size: None,
-                    ty: wgpu::BufferBindingType::Uniform,
-                },
-                count: None,
-            }],
-        });
+/// Creates the render pipeline for each [`TargetFormat`] on first use, all
+/// sharing one shader module and bind group layouts.
+///
+/// Every texture gets its own bind group, so draws can only be batched while
+/// they sample the same texture (or the same [`crate::AtlasOptions`] page).
+///
+/// There is deliberately no bindless pipeline indexing one texture binding
+/// array per vertex, although devices with
+/// [`wgpu::Features::TEXTURE_BINDING_ARRAY`] and
+/// [`wgpu::Features::SAMPLED_TEXTURE_AND_STORAGE_BUFFER_ARRAY_NON_UNIFORM_INDEXING`]
+/// could run one. The WGSL front end of wgpu 0.12 can neither declare binding
+/// arrays nor sample an indexed texture, so its fragment shaders would have to
+/// be a second, hand-written copy of `shader/egui.wgsl` in SPIR-V, passed to
+/// the driver unvalidated through [`wgpu::Features::SPIRV_SHADER_PASSTHROUGH`]
+/// and thus only on Vulkan. It is left for a wgpu whose WGSL has
+/// `binding_array`, so both paths can share one shader source.
+pub struct PipelineCache {
+    module: ShaderModule,
+    pub uniform_bind_group_layout: BindGroupLayout,
+    pub texture_bind_group_layout: BindGroupLayout,
+    pipeline_layout: PipelineLayout,
+    pipelines: AHashMap<PipelineKey, Arc<RenderPipeline>>,
+}
 
-    let uniform_bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
-        label: Some("egui_uniform_bind_group"),
-        layout: &uniform_bind_group_layout,
-        entries: &[wgpu::BindGroupEntry {
-            binding: 0,
-            resource: wgpu::BindingResource::Buffer(wgpu::BufferBinding {
-                buffer: &uniform_buffer.buffer,
-                offset: 0,
-                size: None,
-            }),
-        }],
-    });
-
-    let texture_bind_group_layout =
-        device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
-            label: Some("egui_texture_bind_group_layout"),
-            entries: &[
-                wgpu::BindGroupLayoutEntry {
+impl PipelineCache {
+    pub fn new(device: &Device) -> Self {
+        let shader = wgpu::ShaderModuleDescriptor {
+            label: Some("shader/egui.wgsl"),
+            source: wgpu::ShaderSource::Wgsl(Cow::Borrowed(include_str!("shader/egui.wgsl"))),
+        };
+        let module = device.create_shader_module(&shader);
+
+        let uniform_bind_group_layout =
+            device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+                label: Some("egui_uniform_bind_group_layout"),
+                entries: &[wgpu::BindGroupLayoutEntry {
                     binding: 0,
-                    visibility: wgpu::ShaderStages::FRAGMENT,
-                    ty: wgpu::BindingType::Texture {
-                        multisampled: false,
-                        sample_type: wgpu::TextureSampleType::Float { filterable: true },
-                        view_dimension: wgpu::TextureViewDimension::D2,
-                    },
-                    count: None,
-                },
-                wgpu::BindGroupLayoutEntry {
-                    binding: 1,
-                    visibility: wgpu::ShaderStages::FRAGMENT,
-                    ty: wgpu::BindingType::Sampler(wgpu::SamplerBindingType::Filtering),
-                    count: None,
-                },
-                wgpu::BindGroupLayoutEntry {
-                    binding: 2,
-                    visibility: wgpu::ShaderStages::FRAGMENT,
+                    visibility: wgpu::ShaderStages::VERTEX_FRAGMENT,
                     ty: wgpu::BindingType::Buffer {
-                        ty: BufferBindingType::Uniform,
                         has_dynamic_offset: false,
-                        min_binding_size: NonZeroU64::new(4),
+                        min_binding_size: None,
+                        ty: wgpu::BufferBindingType::Uniform,
                     },
                     count: None,
-                },
-            ],
+                }],
+            });
+
+        let texture_bind_group_layout =
+            device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+                label: Some("egui_texture_bind_group_layout"),
+                entries: &[
+                    wgpu::BindGroupLayoutEntry {
+                        binding: 0,
+                        visibility: wgpu::ShaderStages::FRAGMENT,
+                        ty: wgpu::BindingType::Texture {
+                            multisampled: false,
+                            sample_type: wgpu::TextureSampleType::Float { filterable: true },
+                            view_dimension: wgpu::TextureViewDimension::D2,
+                        },
+                        count: None,
+                    },
+                    wgpu::BindGroupLayoutEntry {
+                        binding: 1,
+                        visibility: wgpu::ShaderStages::FRAGMENT,
+                        ty: wgpu::BindingType::Sampler(wgpu::SamplerBindingType::Filtering),
+                        count: None,
+                    },
+                    wgpu::BindGroupLayoutEntry {
+                        binding: 2,
+                        visibility: wgpu::ShaderStages::FRAGMENT,
+                        ty: wgpu::BindingType::Buffer {
+                            ty: BufferBindingType::Uniform,
+                            has_dynamic_offset: false,
+                            min_binding_size: NonZeroU64::new(4),
+                        },
+                        count: None,
+                    },
+                ],
+            });
+
+        let pipeline_layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+            label: Some("egui_pipeline_layout"),
+            bind_group_layouts: &[&uniform_bind_group_layout, &texture_bind_group_layout],
+            push_constant_ranges: &[],
         });
 
-    let pipeline_layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
-        label: Some("egui_pipeline_layout"),
-        bind_group_layouts: &[&uniform_bind_group_layout, &texture_bind_group_layout],
-        push_constant_ranges: &[],
-    });
+        Self {
+            module,
+            uniform_bind_group_layout,
+            texture_bind_group_layout,
+            pipeline_layout,
+            pipelines: Default::default(),
+        }
+    }
+
+    /// The pipeline drawing to `target_format`, created if this is the first
+    /// time it is asked for.
+    pub fn get(
+        &mut self,
+        device: &Device,
+        target_format: &TargetFormat,
+    ) -> Result<Arc<RenderPipeline>, Error> {
+        let key = target_format.key();
+        if let Some(pipeline) = self.pipelines.get(&key) {
+            return Ok(pipeline.clone());
+        }
+        check_target_format(target_format)?;
+        let pipeline = Arc::new(create_pipeline(
+            device,
+            &self.module,
+            &self.pipeline_layout,
+            target_format,
+        ));
+        self.pipelines.insert(key, pipeline.clone());
+        Ok(pipeline)
+    }
+}
+
+/// Whether texels of `format` are floats, which hold linear colors beyond
+/// `0.0..=1.0`.
+fn is_float_format(format: TextureFormat) -> bool {
+    matches!(
+        format,
+        TextureFormat::R16Float
+            | TextureFormat::Rg16Float
+            | TextureFormat::Rgba16Float
+            | TextureFormat::R32Float
+            | TextureFormat::Rg32Float
+            | TextureFormat::Rgba32Float
+            | TextureFormat::Rg11b10Float
+    )
+}
+
+/// Vertex and fragment entry points of `shader/egui.wgsl` for `output_format`.
+///
+/// sRGB targets get linear colors and let the hardware encode them, float targets
+/// get scaled linear colors, and any other target gets gamma-space colors so it
+/// looks the same once presented.
+fn entry_points(output_format: TextureFormat, hdr: bool) -> (&'static str, &'static str) {
+    if hdr {
+        ("vs_main", "fs_hdr_main")
+    } else if output_format.describe().srgb {
+        ("vs_main", "fs_main")
+    } else {
+        ("vs_conv_main", "fs_conv_main")
+    }
+}
+
+/// Checks that egui can blend into `target_format.format` and that
+/// `target_format.depth_format` is a depth format, using the features every
+/// adapter guarantees, and that wgpu accepts `target_format.sample_count`.
+fn check_target_format(target_format: &TargetFormat) -> Result<(), Error> {
+    let renderable = |format: TextureFormat| {
+        format
+            .describe()
+            .guaranteed_format_features
+            .allowed_usages
+            .contains(TextureUsages::RENDER_ATTACHMENT)
+    };
+    let format = target_format.format;
+    let blendable = matches!(
+        format.describe().sample_type,
+        TextureSampleType::Float { filterable: true }
+    );
+    let sample_count = target_format.sample_count;
+    let valid_sample_count = sample_count.is_power_of_two() && sample_count <= 32;
+    if !renderable(format) || !blendable || !valid_sample_count {
+        return Err(Error::UnsupportedFormat(format));
+    }
+    if let Some(depth_format) = target_format.depth_format {
+        let is_depth = depth_format.describe().sample_type == TextureSampleType::Depth;
+        if !renderable(depth_format) || !is_depth {
+            return Err(Error::UnsupportedFormat(depth_format));
+        }
+    }
+    Ok(())
+}
 
-    let render_pipeline = device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
+fn create_pipeline(
+    device: &Device,
+    module: &ShaderModule,
+    pipeline_layout: &PipelineLayout,
+    target_format: &TargetFormat,
+) -> RenderPipeline {
+    let (vs_entry_point, fs_entry_point) =
+        entry_points(target_format.format, target_format.output_scale().is_some());
+    device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
         label: Some("egui_pipeline"),
-        layout: Some(&pipeline_layout),
+        layout: Some(pipeline_layout),
         vertex: wgpu::VertexState {
-            entry_point: if output_format.describe().srgb {
-                "vs_main"
-            } else {
-                "vs_conv_main"
-            },
-            module: &module,
+            entry_point: vs_entry_point,
+            module,
             buffers: &[wgpu::VertexBufferLayout {
                 array_stride: 5 * 4,
                 step_mode: wgpu::VertexStepMode::Vertex,
@@ -145,18 +309,26 @@ fn create_pipeline(device: &Device, output_format: TextureFormat, msaa_samples:
             polygon_mode: wgpu::PolygonMode::default(),
             strip_index_format: None,
         },
-        depth_stencil: None,
+        depth_stencil: target_format
+            .depth_format
+            .map(|format| wgpu::DepthStencilState {
+                format,
+                depth_write_enabled: false,
+                depth_compare: wgpu::CompareFunction::Always,
+                stencil: Default::default(),
+                bias: Default::default(),
+            }),
         multisample: wgpu::MultisampleState {
             alpha_to_coverage_enabled: false,
-            count: msaa_samples,
+            count: target_format.sample_count,
             mask: !0,
         },
 
         fragment: Some(wgpu::FragmentState {
-            module: &module,
-            entry_point: "fs_main",
+            module,
+            entry_point: fs_entry_point,
             targets: &[wgpu::ColorTargetState {
-                format: output_format,
+                format: target_format.format,
                 blend: Some(wgpu::BlendState {
                     color: wgpu::BlendComponent {
                         src_factor: wgpu::BlendFactor::One,
@@ -173,12 +345,90 @@ fn create_pipeline(device: &Device, output_format: TextureFormat, msaa_samples:
             }],
         }),
         multiview: None,
-    });
-    Pipeline {
-        pipeline: render_pipeline,
-        uniform_bind_group_layout,
-        texture_bind_group_layout,
-        uniform_bind_group,
-        uniform_buffer,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn entry_points_exist_in_shader() {
+        let module = naga::front::wgsl::parse_str(include_str!("shader/egui.wgsl")).unwrap();
+        naga::valid::Validator::new(
+            naga::valid::ValidationFlags::all(),
+            naga::valid::Capabilities::empty(),
+        )
+        .validate(&module)
+        .unwrap();
+
+        let has_entry_point = |name: &str, stage: naga::ShaderStage| {
+            module
+                .entry_points
+                .iter()
+                .any(|ep| ep.name == name && ep.stage == stage)
+        };
+        for format in [
+            TextureFormat::Bgra8UnormSrgb,
+            TextureFormat::Rgba8UnormSrgb,
+            TextureFormat::Bgra8Unorm,
+            TextureFormat::Rgba8Unorm,
+            TextureFormat::Rgb10a2Unorm,
+            TextureFormat::Rgba16Float,
+        ] {
+            for hdr in [false, true] {
+                let (vs, fs) = entry_points(format, hdr);
+                assert!(has_entry_point(vs, naga::ShaderStage::Vertex), "{:?}: missing {}", format, vs);
+                assert!(has_entry_point(fs, naga::ShaderStage::Fragment), "{:?}: missing {}", format, fs);
+            }
+            check_target_format(&TargetFormat::new(format)).unwrap();
+        }
+    }
+
+    #[test]
+    fn float_targets_are_linear() {
+        let float = TargetFormat::new(TextureFormat::Rgba16Float);
+        assert_eq!(float.output_scale(), Some(1.0));
+        assert!(float.key().hdr);
+
+        let srgb = TargetFormat {
+            paper_white: Some(2.0),
+            ..TargetFormat::new(TextureFormat::Bgra8UnormSrgb)
+        };
+        assert_eq!(srgb.output_scale(), None);
+        assert!(!srgb.key().hdr);
+    }
+
+    #[test]
+    fn unsupported_target_formats() {
+        let unsupported = |target_format: TargetFormat| match check_target_format(&target_format) {
+            Err(Error::UnsupportedFormat(format)) => format,
+            other => panic!("{:?}: expected an error, got {:?}", target_format, other),
+        };
+        for format in [
+            TextureFormat::Rgba8Uint,
+            TextureFormat::Rgba32Float,
+            TextureFormat::Depth32Float,
+            TextureFormat::Bc1RgbaUnorm,
+        ] {
+            assert_eq!(unsupported(TargetFormat::new(format)), format);
+        }
+
+        let with_depth = |depth_format| TargetFormat {
+            depth_format: Some(depth_format),
+            ..TargetFormat::new(TextureFormat::Bgra8UnormSrgb)
+        };
+        check_target_format(&with_depth(TextureFormat::Depth24PlusStencil8)).unwrap();
+        for sample_count in [0, 3, 64] {
+            let target_format = TargetFormat {
+                sample_count,
+                ..TargetFormat::new(TextureFormat::Bgra8UnormSrgb)
+            };
+            assert_eq!(unsupported(target_format), TextureFormat::Bgra8UnormSrgb);
+        }
+        assert_eq!(
+            unsupported(with_depth(TextureFormat::Rgba8Unorm)),
+            TextureFormat::Rgba8Unorm
+        );
     }
 }
@@ -0,0 +1,24 @@
+// Downsamples one mip level into the next, see `mipmap.rs`.
+
+struct VertexOutput {
+    [[location(0)]] tex_coord: vec2<f32>;
+    [[builtin(position)]] position: vec4<f32>;
+};
+
+// A triangle covering the whole target, with `tex_coord` 0..1 over the target.
+[[stage(vertex)]]
+fn vs_main([[builtin(vertex_index)]] vertex_index: u32) -> VertexOutput {
+    var out: VertexOutput;
+    let uv = vec2<f32>(f32((vertex_index << 1u) & 2u), f32(vertex_index & 2u));
+    out.tex_coord = uv;
+    out.position = vec4<f32>(uv * vec2<f32>(2.0, -2.0) + vec2<f32>(-1.0, 1.0), 0.0, 1.0);
+    return out;
+}
+
+[[group(0), binding(0)]] var r_tex_color: texture_2d<f32>;
+[[group(0), binding(1)]] var r_tex_sampler: sampler;
+
+[[stage(fragment)]]
+fn fs_main(in: VertexOutput) -> [[location(0)]] vec4<f32> {
+    return textureSample(r_tex_color, r_tex_sampler, in.tex_coord);
+}
@@ -8,6 +8,8 @@ struct VertexOutput {
 
 struct Locals {
     screen_size: vec2<f32>;
+    // Multiplier for linear output colors, only used by `fs_hdr_main`.
+    output_scale: f32;
 };
 [[group(0), binding(0)]] var<uniform> r_locals: Locals;
 
@@ -18,31 +20,58 @@ fn linear_from_srgb(srgb: vec3<f32>) -> vec3<f32> {
     return select(higher, lower, cutoff);
 }
 
-[[stage(vertex)]]
-fn vs_main(
-    [[location(0)]] a_pos: vec2<f32>,
-    [[location(1)]] a_tex_coord: vec2<f32>,
-    [[location(2)]] a_srgba: u32,
-) -> VertexOutput {
-    var out: VertexOutput;
-    out.tex_coord = a_tex_coord;
+fn gamma_from_linear(rgb: vec3<f32>) -> vec3<f32> {
+    let cutoff = rgb < vec3<f32>(0.0031308);
+    let lower = rgb * vec3<f32>(12.92);
+    let higher = vec3<f32>(1.055) * pow(rgb, vec3<f32>(1.0 / 2.4)) - vec3<f32>(0.055);
+    return select(higher, lower, cutoff);
+}
 
-    // [u8; 4] SRGB as u32 -> [r, g, b, a]
-    let color = vec4<f32>(
+// [u8; 4] SRGB as u32 -> [r, g, b, a]
+fn unpack_color(a_srgba: u32) -> vec4<f32> {
+    return vec4<f32>(
         f32(a_srgba & 255u),
         f32((a_srgba >> 8u) & 255u),
         f32((a_srgba >> 16u) & 255u),
         f32((a_srgba >> 24u) & 255u),
     );
-    out.rgba = vec4<f32>(linear_from_srgb(color.rgb), color.a / 255.0);
+}
 
-    out.position = vec4<f32>(
+fn position_from_screen(a_pos: vec2<f32>) -> vec4<f32> {
+    return vec4<f32>(
         2.0 * a_pos.x / r_locals.screen_size.x - 1.0,
         1.0 - 2.0 * a_pos.y / r_locals.screen_size.y,
         0.0,
         1.0,
     );
+}
 
+// For sRGB targets: colors are blended in linear space and encoded by the hardware.
+[[stage(vertex)]]
+fn vs_main(
+    [[location(0)]] a_pos: vec2<f32>,
+    [[location(1)]] a_tex_coord: vec2<f32>,
+    [[location(2)]] a_srgba: u32,
+) -> VertexOutput {
+    var out: VertexOutput;
+    out.tex_coord = a_tex_coord;
+    let color = unpack_color(a_srgba);
+    out.rgba = vec4<f32>(linear_from_srgb(color.rgb), color.a / 255.0);
+    out.position = position_from_screen(a_pos);
+    return out;
+}
+
+// For linear (non-sRGB) targets: colors stay in gamma space and are written as-is.
+[[stage(vertex)]]
+fn vs_conv_main(
+    [[location(0)]] a_pos: vec2<f32>,
+    [[location(1)]] a_tex_coord: vec2<f32>,
+    [[location(2)]] a_srgba: u32,
+) -> VertexOutput {
+    var out: VertexOutput;
+    out.tex_coord = a_tex_coord;
+    out.rgba = unpack_color(a_srgba) / 255.0;
+    out.position = position_from_screen(a_pos);
     return out;
 }
 
@@ -55,8 +84,7 @@ struct TexInfo{
 [[group(1), binding(1)]] var r_tex_sampler: sampler;
 [[group(1), binding(2)]] var<uniform> r_tex_info: TexInfo;
 
-[[stage(fragment)]]
-fn fs_main(in: VertexOutput) -> [[location(0)]] vec4<f32> {
+fn linear_color(in: VertexOutput) -> vec4<f32> {
     let comps = r_tex_info.comps;
     if (comps == 1u) {
         let tex_color = textureSample(r_tex_color, r_tex_sampler, in.tex_coord);
@@ -65,3 +93,29 @@ fn fs_main(in: VertexOutput) -> [[location(0)]] vec4<f32> {
         return in.rgba * textureSample(r_tex_color, r_tex_sampler, in.tex_coord);
     }
 }
+
+[[stage(fragment)]]
+fn fs_main(in: VertexOutput) -> [[location(0)]] vec4<f32> {
+    return linear_color(in);
+}
+
+// Pairs with `vs_conv_main`: sRGB textures are sampled as linear, so they are
+// converted back to gamma space. Single channel textures hold coverage, not color.
+[[stage(fragment)]]
+fn fs_conv_main(in: VertexOutput) -> [[location(0)]] vec4<f32> {
+    let comps = r_tex_info.comps;
+    let tex_color = textureSample(r_tex_color, r_tex_sampler, in.tex_coord);
+    if (comps == 1u) {
+        return in.rgba * tex_color.r;
+    } else {
+        return in.rgba * vec4<f32>(gamma_from_linear(tex_color.rgb), tex_color.a);
+    }
+}
+
+// Pairs with `vs_main` for float (HDR) targets: linear colors scaled so that
+// egui's white ends up at the configured paper white.
+[[stage(fragment)]]
+fn fs_hdr_main(in: VertexOutput) -> [[location(0)]] vec4<f32> {
+    let color = linear_color(in);
+    return vec4<f32>(color.rgb * r_locals.output_scale, color.a);
+}
@@ -0,0 +1,308 @@
+//! Golden image tests for egui UIs.
+//!
+//! A [`Harness`] runs a UI with fixed input on whatever wgpu adapter is
+//! available and compares the result against a checked-in PNG file:
+//!
+//! ```no_run
+//! let mut harness = egui_wgpu::snapshot::Harness::new(320, 240, 1.0).expect("no wgpu adapter");
+//! harness.check("tests/snapshots/hello.png", 1, |ctx| {
+//!     egui::CentralPanel::default().show(ctx, |ui| ui.label("Hello"));
+//! });
+//! ```
+//!
+//! When a check fails, the rendered image is written next to the snapshot as
+//! `*.new.png` together with a `*.diff.png` marking the differing pixels. Run
+//! the tests with the [`UPDATE_ENV_VAR`] environment variable set to `1` to
+//! overwrite the snapshots with the new renderings instead.
+
+use std::path::{Path, PathBuf};
+
+use egui::{Color32, ColorImage};
+
+use crate::{capture, EguiWgpu, Error, TargetFormat};
+
+/// Set to `1` to write rendered images as the new snapshots instead of
+/// comparing against them.
+pub const UPDATE_ENV_VAR: &str = "EGUI_WGPU_UPDATE_SNAPSHOTS";
+
+/// Maximum number of frames [`Harness::render`] runs the UI for, to let egui
+/// settle sizes and layout.
+const MAX_FRAMES: usize = 4;
+
+/// Why a rendered image didn't match its snapshot.
+#[derive(Debug, thiserror::Error)]
+pub enum SnapshotError {
+    /// There is no snapshot file yet.
+    #[error("snapshot {} does not exist", .0.display())]
+    Missing(PathBuf),
+    #[error(
+        "snapshot is {}x{} but the rendering is {}x{}",
+        expected[0], expected[1], actual[0], actual[1]
+    )]
+    SizeMismatch {
+        expected: [usize; 2],
+        actual: [usize; 2],
+    },
+    /// `pixels` pixels differ by more than the tolerance in some channel.
+    #[error("{pixels} pixels differ from the snapshot")]
+    Mismatch { pixels: usize },
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+    #[error("failed to decode snapshot: {0}")]
+    Decode(#[from] png::DecodingError),
+    #[error("failed to encode snapshot: {0}")]
+    Encode(#[from] png::EncodingError),
+    /// The snapshot isn't an 8 bit RGBA PNG file.
+    #[error("snapshot {} is not an 8 bit RGBA PNG", .0.display())]
+    UnsupportedFormat(PathBuf),
+}
+
+/// Renders egui UIs offscreen with fixed input: screen rect, pixels per point
+/// and time.
+pub struct Harness {
+    device: wgpu::Device,
+    queue: wgpu::Queue,
+    egui_wgpu: EguiWgpu,
+    width: u32,
+    height: u32,
+    pixels_per_point: f32,
+    /// Value of [`egui::RawInput::time`] for every frame.
+    pub time: f64,
+}
+
+impl Harness {
+    /// Renders `width` x `height` physical pixels. Returns `None` if there is
+    /// no wgpu adapter, not even a software one.
+    pub fn new(width: u32, height: u32, pixels_per_point: f32) -> Option<Self> {
+        let instance = wgpu::Instance::new(wgpu::Backends::all());
+        let adapter = [false, true].iter().find_map(|&force_fallback_adapter| {
+            pollster::block_on(instance.request_adapter(&wgpu::RequestAdapterOptions {
+                power_preference: wgpu::PowerPreference::default(),
+                compatible_surface: None,
+                force_fallback_adapter,
+            }))
+        })?;
+        let (device, queue) = pollster::block_on(adapter.request_device(
+            &wgpu::DeviceDescriptor {
+                features: wgpu::Features::empty(),
+                limits: wgpu::Limits::downlevel_webgl2_defaults().using_resolution(adapter.limits()),
+                label: Some("egui_snapshot_device"),
+            },
+            None,
+        ))
+        .ok()?;
+
+        let max_texture_side = device.limits().max_texture_dimension_2d as usize;
+        let egui_wgpu = EguiWgpu::new_headless(&device, max_texture_side, pixels_per_point);
+        Some(Self {
+            device,
+            queue,
+            egui_wgpu,
+            width,
+            height,
+            pixels_per_point,
+            time: 0.0,
+        })
+    }
+
+    pub fn egui_ctx(&self) -> &egui::Context {
+        &self.egui_wgpu.egui_ctx
+    }
+
+    /// Runs `run_ui` until egui stops requesting repaints (at most a few
+    /// frames) and returns the last frame.
+    pub fn render(&mut self, mut run_ui: impl FnMut(&egui::Context)) -> Result<ColorImage, Error> {
+        for _ in 0..MAX_FRAMES {
+            let raw_input = egui::RawInput {
+                screen_rect: Some(egui::Rect::from_min_size(
+                    egui::Pos2::ZERO,
+                    egui::vec2(self.width as f32, self.height as f32) / self.pixels_per_point,
+                )),
+                pixels_per_point: Some(self.pixels_per_point),
+                time: Some(self.time),
+                ..Default::default()
+            };
+            let (needs_repaint, _) = self.egui_wgpu.run_with_input(raw_input, &mut run_ui);
+            if !needs_repaint {
+                break;
+            }
+        }
+        self.egui_wgpu.capture(
+            &self.device,
+            &self.queue,
+            TargetFormat::new(wgpu::TextureFormat::Rgba8UnormSrgb),
+            self.width,
+            self.height,
+        )
+    }
+
+    /// Renders `run_ui` and compares it against the PNG at `path`, allowing
+    /// every channel to differ by up to `tolerance`.
+    ///
+    /// # Panics
+    /// If rendering fails or doesn't match, see the [module docs](self).
+    pub fn check(
+        &mut self,
+        path: impl AsRef<Path>,
+        tolerance: u8,
+        run_ui: impl FnMut(&egui::Context),
+    ) {
+        let path = path.as_ref();
+        let image = match self.render(run_ui) {
+            Ok(image) => image,
+            Err(err) => panic!("snapshot {}: failed to render: {}", path.display(), err),
+        };
+        if let Err(err) = check_snapshot(path, &image, tolerance) {
+            panic!("snapshot {}: {}", path.display(), err);
+        }
+    }
+}
+
+/// Compares `image` against the PNG at `path`, allowing every channel to differ
+/// by up to `tolerance`.
+///
+/// On mismatch `image` is written to `*.new.png` and the differences to
+/// `*.diff.png`. With [`UPDATE_ENV_VAR`] set to `1`, `image` overwrites the
+/// snapshot instead.
+pub fn check_snapshot(path: &Path, image: &ColorImage, tolerance: u8) -> Result<(), SnapshotError> {
+    let new_path = path.with_extension("new.png");
+    let diff_path = path.with_extension("diff.png");
+    if std::env::var(UPDATE_ENV_VAR).as_deref() == Ok("1") {
+        write_png(path, image)?;
+        for stale in [&new_path, &diff_path] {
+            if stale.exists() {
+                std::fs::remove_file(stale)?;
+            }
+        }
+        return Ok(());
+    }
+
+    let result = read_png(path).and_then(|expected| {
+        if expected.size != image.size {
+            return Err(SnapshotError::SizeMismatch {
+                expected: expected.size,
+                actual: image.size,
+            });
+        }
+        let diff = diff_image(&expected, image, tolerance);
+        match diff.pixels.iter().filter(|&&p| p == DIFF_COLOR).count() {
+            0 => Ok(()),
+            pixels => {
+                write_png(&diff_path, &diff)?;
+                Err(SnapshotError::Mismatch { pixels })
+            }
+        }
+    });
+    if result.is_err() {
+        write_png(&new_path, image)?;
+    }
+    result
+}
+
+/// Marks pixels that differ in [`diff_image`].
+const DIFF_COLOR: Color32 = Color32::RED;
+
+/// `expected` faded out, with the pixels differing from `actual` by more than
+/// `tolerance` in unmultiplied sRGBA in [`DIFF_COLOR`].
+fn diff_image(expected: &ColorImage, actual: &ColorImage, tolerance: u8) -> ColorImage {
+    let pixels = expected
+        .pixels
+        .iter()
+        .zip(&actual.pixels)
+        .map(|(expected, actual)| {
+            let differs = expected
+                .to_srgba_unmultiplied()
+                .iter()
+                .zip(actual.to_srgba_unmultiplied())
+                .any(|(&e, a)| e.abs_diff(a) > tolerance);
+            if differs {
+                DIFF_COLOR
+            } else {
+                expected.linear_multiply(0.25)
+            }
+        })
+        .collect();
+    ColorImage {
+        size: expected.size,
+        pixels,
+    }
+}
+
+fn write_png(path: &Path, image: &ColorImage) -> Result<(), SnapshotError> {
+    if let Some(dir) = path.parent() {
+        std::fs::create_dir_all(dir)?;
+    }
+    let bytes = capture::encode_png(image)?;
+    std::fs::write(path, bytes)?;
+    Ok(())
+}
+
+fn read_png(path: &Path) -> Result<ColorImage, SnapshotError> {
+    let file = match std::fs::File::open(path) {
+        Ok(file) => file,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => {
+            return Err(SnapshotError::Missing(path.to_owned()));
+        }
+        Err(err) => return Err(err.into()),
+    };
+    let mut reader = png::Decoder::new(file).read_info()?;
+    let mut data = vec![0; reader.output_buffer_size()];
+    let info = reader.next_frame(&mut data)?;
+    if info.color_type != png::ColorType::Rgba || info.bit_depth != png::BitDepth::Eight {
+        return Err(SnapshotError::UnsupportedFormat(path.to_owned()));
+    }
+    Ok(ColorImage::from_rgba_unmultiplied(
+        [info.width as usize, info.height as usize],
+        &data[..info.width as usize * info.height as usize * 4],
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn checkerboard(size: [usize; 2], color: Color32) -> ColorImage {
+        let mut image = ColorImage::new(size, Color32::TRANSPARENT);
+        for (i, pixel) in image.pixels.iter_mut().enumerate() {
+            let (x, y) = (i % size[0], i / size[0]);
+            if (x + y) & 1 == 0 {
+                *pixel = color;
+            }
+        }
+        image
+    }
+
+    #[test]
+    fn diff_respects_tolerance() {
+        let expected = checkerboard([4, 4], Color32::from_rgb(100, 100, 100));
+        let actual = checkerboard([4, 4], Color32::from_rgb(102, 100, 100));
+        let count = |diff: ColorImage| diff.pixels.iter().filter(|&&p| p == DIFF_COLOR).count();
+        assert_eq!(count(diff_image(&expected, &actual, 2)), 0);
+        assert_eq!(count(diff_image(&expected, &actual, 1)), 8);
+    }
+
+    #[test]
+    fn mismatch_writes_new_and_diff_images() {
+        let dir = std::env::temp_dir().join(format!("egui_wgpu_snapshot_{}", std::process::id()));
+        let path = dir.join("checkerboard.png");
+        let image = checkerboard([3, 2], Color32::from_rgba_unmultiplied(10, 20, 30, 40));
+
+        assert!(matches!(
+            check_snapshot(&path, &image, 0),
+            Err(SnapshotError::Missing(_))
+        ));
+        assert!(path.with_extension("new.png").exists());
+
+        write_png(&path, &image).unwrap();
+        check_snapshot(&path, &image, 0).unwrap();
+
+        let other = checkerboard([3, 2], Color32::WHITE);
+        assert!(matches!(
+            check_snapshot(&path, &other, 0),
+            Err(SnapshotError::Mismatch { pixels: 3 })
+        ));
+        assert!(path.with_extension("diff.png").exists());
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
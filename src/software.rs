@@ -0,0 +1,345 @@
+//! A CPU reference implementation of the painter and `shader/egui.wgsl`.
+//!
+//! [`SoftwareRenderer`] follows the same rules as the GPU path rendering to an
+//! `Rgba8UnormSrgb` target: sRGB vertex colors are decoded to linear, single
+//! channel textures are coverage, colors are blended premultiplied with
+//! `One, OneMinusSrcAlpha` and stored as 8 bit sRGB after every draw, and
+//! clip rects are turned into scissor rects the same way. This makes it usable
+//! for rendering tests on machines without a GPU, and as a fallback renderer.
+
+use ahash::AHashMap;
+use egui::{ClippedMesh, Color32, ColorImage, ImageData, Pos2, Rgba};
+
+use crate::{painter::scissor_rect, Error};
+
+/// Renders tessellated egui output into a [`ColorImage`] on the CPU.
+///
+/// Textures are sampled with the painter's default [`crate::SamplerOptions`]:
+/// nearest filtering, clamped to the edge.
+#[derive(Default)]
+pub struct SoftwareRenderer {
+    textures: AHashMap<egui::TextureId, ImageData>,
+}
+
+impl SoftwareRenderer {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Applies `textures_delta` and draws `clipped_meshes` onto a transparent
+    /// `width` x `height` image, like [`crate::EguiWgpu::paint`] would.
+    ///
+    /// Fails with [`Error::TextureSizeMismatch`] if a partial update doesn't
+    /// fit into the texture it updates.
+    pub fn render(
+        &mut self,
+        width: u32,
+        height: u32,
+        pixels_per_point: f32,
+        clipped_meshes: &[ClippedMesh],
+        textures_delta: &egui::TexturesDelta,
+    ) -> Result<ColorImage, Error> {
+        for (id, image_delta) in &textures_delta.set {
+            self.set_texture(*id, image_delta)?;
+        }
+
+        let mut image = ColorImage::new([width as usize, height as usize], Color32::TRANSPARENT);
+        for ClippedMesh(clip_rect, mesh) in clipped_meshes {
+            let scissor = match scissor_rect(*clip_rect, pixels_per_point, width, height) {
+                Some(scissor) => scissor,
+                None => continue,
+            };
+            let texture = match self.textures.get(&mesh.texture_id) {
+                Some(texture) => texture,
+                None => continue,
+            };
+            for triangle in mesh.indices.chunks_exact(3) {
+                let vertices = [
+                    &mesh.vertices[triangle[0] as usize],
+                    &mesh.vertices[triangle[1] as usize],
+                    &mesh.vertices[triangle[2] as usize],
+                ];
+                draw_triangle(&mut image, scissor, pixels_per_point, texture, vertices);
+            }
+        }
+
+        for id in &textures_delta.free {
+            self.textures.remove(id);
+        }
+        Ok(image)
+    }
+
+    fn set_texture(
+        &mut self,
+        id: egui::TextureId,
+        delta: &egui::epaint::ImageDelta,
+    ) -> Result<(), Error> {
+        let pos = match delta.pos {
+            Some(pos) => pos,
+            None => {
+                self.textures.insert(id, delta.image.clone());
+                return Ok(());
+            }
+        };
+        let size = delta.image.size();
+        if let Some(texture) = self.textures.get(&id) {
+            let tex_size = texture.size();
+            if pos[0] + size[0] > tex_size[0] || pos[1] + size[1] > tex_size[1] {
+                return Err(Error::TextureSizeMismatch {
+                    id,
+                    size,
+                    reason: format!(
+                        "at {:?} doesn't fit into the texture of size {:?}",
+                        pos, tex_size
+                    ),
+                });
+            }
+        }
+        match (self.textures.get_mut(&id), &delta.image) {
+            (Some(ImageData::Color(dst)), ImageData::Color(src)) => {
+                copy_region(&mut dst.pixels, dst.size[0], &src.pixels, src.size, pos);
+            }
+            (Some(ImageData::Alpha(dst)), ImageData::Alpha(src)) => {
+                copy_region(&mut dst.pixels, dst.size[0], &src.pixels, src.size, pos);
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+}
+
+fn copy_region<T: Copy>(
+    dst: &mut [T],
+    dst_width: usize,
+    src: &[T],
+    src_size: [usize; 2],
+    pos: [usize; 2],
+) {
+    for (y, row) in src.chunks_exact(src_size[0]).enumerate() {
+        let start = (pos[1] + y) * dst_width + pos[0];
+        dst[start..start + src_size[0]].copy_from_slice(row);
+    }
+}
+
+/// `linear_from_srgb` of the shader, for a single 0-255 channel.
+fn linear_from_srgb(srgb: f32) -> f32 {
+    if srgb < 10.31475 {
+        srgb / 3294.6
+    } else {
+        ((srgb + 14.025) / 269.025).powf(2.4)
+    }
+}
+
+/// Vertex color as output by `vs_main`.
+fn vertex_color(color: Color32) -> Rgba {
+    Rgba::from_rgba_premultiplied(
+        linear_from_srgb(color.r() as f32),
+        linear_from_srgb(color.g() as f32),
+        linear_from_srgb(color.b() as f32),
+        color.a() as f32 / 255.0,
+    )
+}
+
+/// `textureSample` with nearest filtering and clamping, as a linear color for
+/// sRGB textures or as coverage in every channel for single channel ones.
+fn sample(texture: &ImageData, uv: Pos2) -> Rgba {
+    let [width, height] = texture.size();
+    let x = ((uv.x * width as f32).floor().max(0.0) as usize).min(width - 1);
+    let y = ((uv.y * height as f32).floor().max(0.0) as usize).min(height - 1);
+    match texture {
+        ImageData::Color(image) => Rgba::from(image.pixels[y * width + x]),
+        ImageData::Alpha(image) => {
+            let coverage = image.pixels[y * width + x] as f32 / 255.0;
+            Rgba::from_rgba_premultiplied(coverage, coverage, coverage, coverage)
+        }
+    }
+}
+
+/// Edge function of `p` against the edge `a -> b`, positive on the inside.
+fn edge(a: Pos2, b: Pos2, p: Pos2) -> f32 {
+    (b.x - a.x) * (p.y - a.y) - (b.y - a.y) * (p.x - a.x)
+}
+
+/// Whether `a -> b` is a top or left edge, which own the pixels centered on them.
+fn is_top_left(a: Pos2, b: Pos2) -> bool {
+    (a.y == b.y && b.x > a.x) || b.y < a.y
+}
+
+fn draw_triangle(
+    image: &mut ColorImage,
+    [scissor_x, scissor_y, scissor_width, scissor_height]: [u32; 4],
+    pixels_per_point: f32,
+    texture: &ImageData,
+    vertices: [&egui::epaint::Vertex; 3],
+) {
+    let mut pos = vertices.map(|v| (v.pos.to_vec2() * pixels_per_point).to_pos2());
+    let mut vertices = vertices;
+    let mut area = edge(pos[0], pos[1], pos[2]);
+    if area == 0.0 {
+        return;
+    }
+    if area < 0.0 {
+        pos.swap(1, 2);
+        vertices.swap(1, 2);
+        area = -area;
+    }
+    let colors = vertices.map(|v| vertex_color(v.color));
+
+    let min_x = pos.iter().map(|p| p.x).fold(f32::INFINITY, f32::min);
+    let max_x = pos.iter().map(|p| p.x).fold(f32::NEG_INFINITY, f32::max);
+    let min_y = pos.iter().map(|p| p.y).fold(f32::INFINITY, f32::min);
+    let max_y = pos.iter().map(|p| p.y).fold(f32::NEG_INFINITY, f32::max);
+    let x_range = (min_x.floor().max(scissor_x as f32) as u32)
+        ..(max_x.ceil().min((scissor_x + scissor_width) as f32).max(0.0) as u32);
+    let y_range = (min_y.floor().max(scissor_y as f32) as u32)
+        ..(max_y.ceil().min((scissor_y + scissor_height) as f32).max(0.0) as u32);
+
+    let edges = [(1, 2), (2, 0), (0, 1)];
+    let image_width = image.size[0];
+    for y in y_range {
+        for x in x_range.clone() {
+            let p = Pos2::new(x as f32 + 0.5, y as f32 + 0.5);
+            let mut weights = [0.0; 3];
+            let mut inside = true;
+            for (i, &(a, b)) in edges.iter().enumerate() {
+                let w = edge(pos[a], pos[b], p);
+                if w < 0.0 || (w == 0.0 && !is_top_left(pos[a], pos[b])) {
+                    inside = false;
+                    break;
+                }
+                weights[i] = w / area;
+            }
+            if !inside {
+                continue;
+            }
+
+            let uv = (0..3).fold(Pos2::ZERO, |uv, i| uv + vertices[i].uv.to_vec2() * weights[i]);
+            let color = (0..3).fold(Rgba::TRANSPARENT, |c, i| c + colors[i] * weights[i]);
+            let src = color * sample(texture, uv);
+
+            let pixel = &mut image.pixels[y as usize * image_width + x as usize];
+            let dst = Rgba::from(*pixel);
+            // color: One, OneMinusSrcAlpha; alpha: OneMinusDstAlpha, One.
+            let blended = Rgba::from_rgba_premultiplied(
+                src.r() + dst.r() * (1.0 - src.a()),
+                src.g() + dst.g() * (1.0 - src.a()),
+                src.b() + dst.b() * (1.0 - src.a()),
+                src.a() * (1.0 - dst.a()) + dst.a(),
+            );
+            *pixel = Color32::from(blended);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use egui::{epaint::Mesh, pos2, Rect, TextureId};
+
+    /// A 1x1 font texture, which `Mesh::add_colored_rect` samples.
+    fn font_texture(image: impl Into<ImageData>) -> egui::TexturesDelta {
+        let mut delta = egui::TexturesDelta::default();
+        delta.set.insert(
+            TextureId::Managed(0),
+            egui::epaint::ImageDelta::full(image),
+        );
+        delta
+    }
+
+    fn white_texture() -> egui::TexturesDelta {
+        font_texture(ColorImage::new([1, 1], Color32::WHITE))
+    }
+
+    fn rect_mesh(rect: Rect, color: Color32) -> Mesh {
+        let mut mesh = Mesh::default();
+        mesh.add_colored_rect(rect, color);
+        mesh
+    }
+
+    fn assert_pixels(image: &ColorImage, expected: impl Fn(usize, usize) -> Color32) {
+        for y in 0..image.size[1] {
+            for x in 0..image.size[0] {
+                let pixel = image.pixels[y * image.size[0] + x];
+                assert_eq!(pixel, expected(x, y), "pixel {} {}", x, y);
+            }
+        }
+    }
+
+    #[test]
+    fn opaque_rect_covers_exactly_its_pixels() {
+        let rect = Rect::from_min_max(pos2(1.0, 1.0), pos2(3.0, 4.0));
+        let meshes = [ClippedMesh(Rect::EVERYTHING, rect_mesh(rect, Color32::RED))];
+        let image = SoftwareRenderer::new().render(5, 5, 1.0, &meshes, &white_texture()).unwrap();
+        assert_pixels(&image, |x, y| {
+            if (1..3).contains(&x) && (1..4).contains(&y) {
+                Color32::RED
+            } else {
+                Color32::TRANSPARENT
+            }
+        });
+    }
+
+    #[test]
+    fn alpha_texture_is_coverage() {
+        let mut coverage = egui::AlphaImage::new([1, 1]);
+        let rect = Rect::from_min_max(pos2(0.0, 0.0), pos2(2.0, 2.0));
+        let meshes = [ClippedMesh(Rect::EVERYTHING, rect_mesh(rect, Color32::WHITE))];
+        let image = SoftwareRenderer::new()
+            .render(2, 2, 1.0, &meshes, &font_texture(coverage.clone()))
+            .unwrap();
+        assert_pixels(&image, |_, _| Color32::TRANSPARENT);
+
+        coverage.pixels[0] = 255;
+        let image = SoftwareRenderer::new()
+            .render(2, 2, 1.0, &meshes, &font_texture(coverage))
+            .unwrap();
+        assert_pixels(&image, |_, _| Color32::WHITE);
+    }
+
+    #[test]
+    fn clip_rect_is_scaled_and_applied() {
+        let rect = Rect::from_min_max(pos2(0.0, 0.0), pos2(4.0, 4.0));
+        let clip = Rect::from_min_max(pos2(1.0, 0.0), pos2(2.0, 4.0));
+        let meshes = [ClippedMesh(clip, rect_mesh(rect, Color32::BLUE))];
+        let image = SoftwareRenderer::new().render(8, 8, 2.0, &meshes, &white_texture()).unwrap();
+        assert_pixels(&image, |x, _| {
+            if (2..4).contains(&x) {
+                Color32::BLUE
+            } else {
+                Color32::TRANSPARENT
+            }
+        });
+    }
+
+    #[test]
+    fn translucent_blend_is_premultiplied() {
+        let rect = Rect::from_min_max(pos2(0.0, 0.0), pos2(1.0, 1.0));
+        let half_white = Color32::from_white_alpha(128);
+        let mut mesh = rect_mesh(rect, Color32::BLACK);
+        mesh.append(rect_mesh(rect, half_white));
+        let meshes = [ClippedMesh(Rect::EVERYTHING, mesh)];
+        let image = SoftwareRenderer::new().render(1, 1, 1.0, &meshes, &white_texture()).unwrap();
+        let expected = Color32::from(
+            Rgba::from(half_white) + Rgba::from(Color32::BLACK) * (1.0 - 128.0 / 255.0),
+        );
+        assert_eq!(image.pixels[0], expected);
+    }
+
+    #[test]
+    fn partial_update_out_of_bounds_is_an_error() {
+        let mut renderer = SoftwareRenderer::new();
+        renderer.render(1, 1, 1.0, &[], &white_texture()).unwrap();
+
+        let mut delta = egui::TexturesDelta::default();
+        delta.set.insert(
+            TextureId::Managed(0),
+            egui::epaint::ImageDelta::partial([1, 0], ColorImage::new([1, 1], Color32::RED)),
+        );
+        match renderer.render(1, 1, 1.0, &[], &delta) {
+            Err(Error::TextureSizeMismatch { id, size, .. }) => {
+                assert_eq!((id, size), (TextureId::Managed(0), [1, 1]));
+            }
+            other => panic!("expected a size mismatch, got {:?}", other.map(|_| ())),
+        }
+    }
+}
@@ -0,0 +1,161 @@
+use std::{
+    future::Future,
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+use wgpu::{Buffer, CommandEncoder, Device, QuerySet};
+
+use crate::waker::noop_waker;
+
+/// Frames whose timestamps can be waiting for read back at once. Frames
+/// painted while all of them are busy aren't timed.
+const FRAMES_IN_FLIGHT: usize = 3;
+
+/// Size of the two resolved `u64` timestamps of one frame.
+const SLOT_SIZE: u64 = 2 * std::mem::size_of::<u64>() as u64;
+
+type MapFuture = Pin<Box<dyn Future<Output = Result<(), wgpu::BufferAsyncError>> + Send>>;
+
+enum SlotState {
+    Free,
+    /// Timestamps resolved into the buffer by an encoder that may not have been
+    /// submitted yet.
+    Written,
+    Mapping(MapFuture),
+}
+
+/// Read back buffer for the timestamps of one frame.
+struct Slot {
+    buffer: Buffer,
+    state: SlotState,
+}
+
+/// Measures the GPU time of the egui render pass with timestamp queries.
+///
+/// Timestamps are written around the pass, resolved into one of a few buffers
+/// and mapped on a later frame, so reading them never waits for the GPU.
+pub struct GpuTimer {
+    query_set: QuerySet,
+    /// Nanoseconds per timestamp tick.
+    period: f32,
+    slots: Vec<Slot>,
+    next: usize,
+    last_ms: Option<f32>,
+}
+
+impl GpuTimer {
+    /// Returns `None` if `device` wasn't created with
+    /// [`wgpu::Features::TIMESTAMP_QUERY`].
+    pub fn new(device: &Device, queue: &wgpu::Queue) -> Option<Self> {
+        if !device.features().contains(wgpu::Features::TIMESTAMP_QUERY) {
+            return None;
+        }
+        let query_set = device.create_query_set(&wgpu::QuerySetDescriptor {
+            label: Some("egui_timestamp_query_set"),
+            ty: wgpu::QueryType::Timestamp,
+            count: 2 * FRAMES_IN_FLIGHT as u32,
+        });
+        let slots = (0..FRAMES_IN_FLIGHT)
+            .map(|_| Slot {
+                buffer: device.create_buffer(&wgpu::BufferDescriptor {
+                    label: Some("egui_timestamp_buffer"),
+                    size: SLOT_SIZE,
+                    usage: wgpu::BufferUsages::COPY_DST | wgpu::BufferUsages::MAP_READ,
+                    mapped_at_creation: false,
+                }),
+                state: SlotState::Free,
+            })
+            .collect();
+        Some(Self {
+            query_set,
+            period: queue.get_timestamp_period(),
+            slots,
+            next: 0,
+            last_ms: None,
+        })
+    }
+
+    /// GPU time of the most recent frame read back, in milliseconds.
+    pub fn last_ms(&self) -> Option<f32> {
+        self.last_ms
+    }
+
+    /// Reads back finished frames and writes the start timestamp of this one
+    /// into `encoder`. Returns the slot to pass to [`Self::end`], `None` if all
+    /// slots are still waiting for the GPU.
+    ///
+    /// The encoder passed to the previous `end` must have been submitted.
+    pub fn begin(&mut self, device: &Device, encoder: &mut CommandEncoder) -> Option<usize> {
+        self.read_back(device);
+        let slot = self.next;
+        if !matches!(self.slots[slot].state, SlotState::Free) {
+            return None;
+        }
+        self.next = (slot + 1) % FRAMES_IN_FLIGHT;
+        encoder.write_timestamp(&self.query_set, 2 * slot as u32);
+        Some(slot)
+    }
+
+    /// Writes the end timestamp of the frame started by [`Self::begin`] and
+    /// resolves both into the slot's buffer.
+    pub fn end(&mut self, encoder: &mut CommandEncoder, slot: usize) {
+        let first = 2 * slot as u32;
+        encoder.write_timestamp(&self.query_set, first + 1);
+        encoder.resolve_query_set(&self.query_set, first..first + 2, &self.slots[slot].buffer, 0);
+        self.slots[slot].state = SlotState::Written;
+    }
+
+    /// Starts mapping the slots written by previous frames and collects the
+    /// ones whose mapping finished, without blocking.
+    fn read_back(&mut self, device: &Device) {
+        for slot in &mut self.slots {
+            if let SlotState::Written = slot.state {
+                let mapping = slot.buffer.slice(..).map_async(wgpu::MapMode::Read);
+                slot.state = SlotState::Mapping(Box::pin(mapping));
+            }
+        }
+        device.poll(wgpu::Maintain::Poll);
+
+        let waker = noop_waker();
+        let mut cx = Context::from_waker(&waker);
+        for slot in &mut self.slots {
+            let result = match &mut slot.state {
+                SlotState::Mapping(mapping) => match mapping.as_mut().poll(&mut cx) {
+                    Poll::Ready(result) => result,
+                    Poll::Pending => continue,
+                },
+                _ => continue,
+            };
+            slot.state = SlotState::Free;
+            if let Err(err) = result {
+                log::warn!("failed to read back egui GPU timestamps: {}", err);
+                continue;
+            }
+            let data = slot.buffer.slice(..).get_mapped_range();
+            let timestamps: &[u64] = bytemuck::cast_slice(&data);
+            self.last_ms = Some(elapsed_ms(timestamps[0], timestamps[1], self.period));
+            drop(data);
+            slot.buffer.unmap();
+        }
+    }
+}
+
+/// Milliseconds between the `start` and `end` timestamps, `period` nanoseconds
+/// per tick apart.
+fn elapsed_ms(start: u64, end: u64, period: f32) -> f32 {
+    (end.saturating_sub(start) as f64 * period as f64 / 1_000_000.0) as f32
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn elapsed_ms_scales_by_period() {
+        assert_eq!(elapsed_ms(1_000, 3_000_000, 1.0), 2.999);
+        assert_eq!(elapsed_ms(0, 1_000, 40.0), 0.04);
+        // Timestamps going backwards, e.g. after a GPU reset.
+        assert_eq!(elapsed_ms(10, 5, 1.0), 0.0);
+    }
+}
@@ -0,0 +1,108 @@
+use std::{
+    future::Future,
+    num::NonZeroU64,
+    pin::Pin,
+    task::Context,
+};
+
+use wgpu::{util::StagingBelt, Buffer, CommandEncoder, Device};
+
+use crate::waker::noop_waker;
+
+/// Size of the staging buffers of [`BufferUpload::StagingBelt`]. Frames
+/// uploading more than that get a staging buffer of their size.
+const STAGING_CHUNK_SIZE: u64 = 256 * 1024;
+
+/// How vertex, index and uniform data is uploaded, see
+/// [`crate::EguiWgpu::with_buffer_upload`].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum BufferUpload {
+    /// [`wgpu::Queue::write_buffer`], which allocates staging memory for every
+    /// write.
+    #[default]
+    WriteBuffer,
+    /// A [`StagingBelt`]: data is written into recycled, mapped staging buffers
+    /// and copied into place by a command encoder submitted after each upload.
+    StagingBelt,
+}
+
+/// Writes buffers with the chosen [`BufferUpload`].
+pub struct Uploader {
+    method: BufferUpload,
+    belt: StagingBelt,
+    /// Copies recorded by the belt since the last [`Self::submit`].
+    encoder: Option<CommandEncoder>,
+    /// Returns staging buffers to the belt once the GPU is done with them.
+    recalls: Vec<Pin<Box<dyn Future<Output = ()> + Send>>>,
+}
+
+impl Default for Uploader {
+    fn default() -> Self {
+        Self::new(BufferUpload::default())
+    }
+}
+
+impl Uploader {
+    pub fn new(method: BufferUpload) -> Self {
+        Self {
+            method,
+            belt: StagingBelt::new(STAGING_CHUNK_SIZE),
+            encoder: None,
+            recalls: Vec::new(),
+        }
+    }
+
+    pub fn method(&self) -> BufferUpload {
+        self.method
+    }
+
+    /// Writes `data` into `buffer` at `offset`. `data.len()` must be a multiple
+    /// of [`wgpu::COPY_BUFFER_ALIGNMENT`].
+    ///
+    /// With [`BufferUpload::StagingBelt`] the write only takes effect with the
+    /// next [`Self::submit`].
+    pub fn write(
+        &mut self,
+        device: &Device,
+        queue: &wgpu::Queue,
+        buffer: &Buffer,
+        offset: u64,
+        data: &[u8],
+    ) {
+        let size = match NonZeroU64::new(data.len() as u64) {
+            Some(size) => size,
+            None => return,
+        };
+        match self.method {
+            BufferUpload::WriteBuffer => queue.write_buffer(buffer, offset, data),
+            BufferUpload::StagingBelt => {
+                let encoder = self.encoder.get_or_insert_with(|| {
+                    device.create_command_encoder(&wgpu::CommandEncoderDescriptor {
+                        label: Some("egui-upload-encoder"),
+                    })
+                });
+                self.belt
+                    .write_buffer(encoder, buffer, offset, size, device)
+                    .copy_from_slice(data);
+            }
+        }
+    }
+
+    /// Submits the copies recorded since the last call and takes back staging
+    /// buffers the GPU is done with, without blocking.
+    pub fn submit(&mut self, device: &Device, queue: &wgpu::Queue) {
+        if let Some(encoder) = self.encoder.take() {
+            self.belt.finish();
+            queue.submit(Some(encoder.finish()));
+            self.recalls.push(Box::pin(self.belt.recall()));
+        }
+        if self.recalls.is_empty() {
+            return;
+        }
+        device.poll(wgpu::Maintain::Poll);
+        let waker = noop_waker();
+        let mut cx = Context::from_waker(&waker);
+        self.recalls
+            .retain_mut(|recall| recall.as_mut().poll(&mut cx).is_pending());
+    }
+}
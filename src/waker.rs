@@ -0,0 +1,32 @@
+use std::{
+    future::Future,
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll, Wake, Waker},
+};
+
+use wgpu::Device;
+
+/// A waker for polling wgpu futures, which are driven by [`wgpu::Device::poll`]
+/// and polled again afterwards instead of being woken.
+pub(crate) fn noop_waker() -> Waker {
+    struct NoopWake;
+    impl Wake for NoopWake {
+        fn wake(self: Arc<Self>) {}
+    }
+    Waker::from(Arc::new(NoopWake))
+}
+
+/// Blocks until `future` is ready, waiting on `device` for the GPU work it
+/// depends on, e.g. a buffer mapping.
+pub(crate) fn block_on<F: Future>(device: &Device, future: F) -> F::Output {
+    let mut future = Box::pin(future);
+    let waker = noop_waker();
+    let mut cx = Context::from_waker(&waker);
+    loop {
+        device.poll(wgpu::Maintain::Wait);
+        if let Poll::Ready(output) = Pin::as_mut(&mut future).poll(&mut cx) {
+            return output;
+        }
+    }
+}